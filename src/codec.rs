@@ -0,0 +1,82 @@
+use super::network::RpcMessage;
+
+use bincode::Options;
+use std::fmt::{Display, Error, Formatter};
+
+// first byte of every binary encoded message, JSON messages always start with '{'
+const MAGIC: u8 = 0xDA;
+
+// bumped every time the binary layout of RpcMessage changes
+pub const VERSION: u8 = 7;
+
+// Wire encoding used by a node for the messages it sends.
+// Decoding doesn't depend on the node's codec: both encodings are recognized,
+// so nodes using different codecs can still talk to each other
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    // MAGIC, VERSION, then bincode with varint integers
+    #[default]
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Empty,
+    UnknownFormat(u8),
+    UnsupportedVersion(u8),
+    Json(serde_json::Error),
+    Binary(bincode::Error),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            CodecError::Empty => write!(f, "empty payload"),
+            CodecError::UnknownFormat(b) => write!(f, "unknown payload format (0x{:02X})", b),
+            CodecError::UnsupportedVersion(v) => write!(f, "unsupported binary version {}", v),
+            CodecError::Json(e) => write!(f, "json: {}", e),
+            CodecError::Binary(e) => write!(f, "binary: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+impl Codec {
+    pub fn encode(&self, msg: &RpcMessage) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Codec::Json => serde_json::to_vec(msg).map_err(CodecError::Json),
+            Codec::Binary => {
+                let mut buf = vec![MAGIC, VERSION];
+                bincode_options()
+                    .serialize_into(&mut buf, msg)
+                    .map_err(CodecError::Binary)?;
+
+                Ok(buf)
+            }
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<RpcMessage, CodecError> {
+        match buf.first() {
+            None => Err(CodecError::Empty),
+            Some(&MAGIC) => match buf.get(1) {
+                // nothing inside a message can be larger than the message itself,
+                // the limit stops bogus length prefixes from allocating huge buffers
+                Some(&VERSION) => bincode_options()
+                    .with_limit(buf.len() as u64)
+                    .deserialize(&buf[2..])
+                    .map_err(CodecError::Binary),
+                Some(&v) => Err(CodecError::UnsupportedVersion(v)),
+                None => Err(CodecError::Empty),
+            },
+            Some(b'{') => serde_json::from_slice(buf).map_err(CodecError::Json),
+            Some(&b) => Err(CodecError::UnknownFormat(b)),
+        }
+    }
+}
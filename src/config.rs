@@ -0,0 +1,414 @@
+use super::codec::Codec;
+use super::error::KademliaError;
+use super::fragment;
+use super::identity::Identity;
+use super::node::Node;
+use super::protocol::Protocol;
+use super::puzzle;
+use super::routing::IpLimits;
+use super::storage::{MemoryStorage, StorageBackend};
+use super::transport::{Transport, UdpTransport};
+use super::utils;
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Tunable parameters of a single Protocol instance,
+// nodes in the same process can use different ones
+#[derive(Debug, Clone)]
+pub struct ProtocolConfig {
+    // replication factor and size of the k-buckets
+    pub k_param: usize,
+    // number of concurrent queries during a lookup
+    pub alpha: usize,
+    // how long to wait for a response
+    pub timeout: Duration,
+    // largest datagram sent, bigger messages are fragmented. Datagrams up to 65535 bytes
+    // are received whatever its value, so nodes with different ones understand each other
+    pub buf_size: usize,
+    pub verbose: bool,
+    // how often the pairs we put get put again, for publish_lifetime after the last put
+    pub publish_interval: Duration,
+    pub publish_lifetime: Duration,
+    // how often replicas get put again, unless another node stored them meanwhile
+    pub republish_interval: Duration,
+    // buckets without any activity for this long get refreshed with a lookup
+    pub refresh_interval: Duration,
+    // lifetime of the pairs stored by put
+    pub ttl: Duration,
+    // how often expired pairs are removed from the store
+    pub sweep_interval: Duration,
+    // a new contact gets at most handoff_max_keys pairs we store, once every handoff_cooldown,
+    // and all of them together at most handoff_max_stores every handoff_cooldown
+    pub handoff_max_keys: usize,
+    pub handoff_cooldown: Duration,
+    pub handoff_max_stores: usize,
+    // where the routing table contacts are saved every contacts_save_interval,
+    // they're loaded back on start. Nothing is saved when it's None
+    pub contacts_file: Option<PathBuf>,
+    pub contacts_save_interval: Duration,
+    // every bootstrap node is pinged up to 1 + bootstrap_retries times,
+    // waiting bootstrap_backoff before the first retry and twice as long before each next one,
+    // up to protocol::MAX_BACKOFF
+    pub bootstrap_retries: u32,
+    pub bootstrap_backoff: Duration,
+    // messages are sent unencrypted, handy with Codec::Json to read the traffic.
+    // Encrypted messages are still understood
+    pub plaintext: bool,
+    // handshakes an IP can start with us every second, see session.rs
+    pub max_handshakes: usize,
+    // node ids must solve S/Kademlia puzzles of these difficulties (leading zero bits),
+    // 0 disables a puzzle. They must be the same on every node of the network, see puzzle.rs
+    pub static_difficulty: u32,
+    pub dynamic_difficulty: u32,
+    // lookups run along this many disjoint paths (S/Kademlia) and put stores on the
+    // k_param closest nodes of each one, so a few malicious nodes can't steer them all
+    pub disjoint_paths: usize,
+    // caps on the contacts sharing an IP or a subnet, see IpLimits.
+    // IpLimits::disabled() lifts them when every node runs on the same host
+    pub ip_limits: IpLimits,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            k_param: super::K_PARAM,
+            alpha: super::ALPHA,
+            timeout: Duration::from_millis(super::TIMEOUT),
+            buf_size: super::BUF_SIZE,
+            verbose: super::VERBOSE,
+            publish_interval: Duration::from_secs(24 * 60 * 60),
+            publish_lifetime: Duration::from_secs(30 * 24 * 60 * 60),
+            republish_interval: Duration::from_secs(60 * 60),
+            refresh_interval: Duration::from_secs(60 * 60),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            sweep_interval: Duration::from_secs(60),
+            handoff_max_keys: 100,
+            handoff_cooldown: Duration::from_secs(10 * 60),
+            handoff_max_stores: 1000,
+            contacts_file: None,
+            contacts_save_interval: Duration::from_secs(10 * 60),
+            bootstrap_retries: 3,
+            bootstrap_backoff: Duration::from_secs(1),
+            plaintext: false,
+            max_handshakes: 8,
+            static_difficulty: 0,
+            dynamic_difficulty: 0,
+            disjoint_paths: 1,
+            ip_limits: IpLimits::default(),
+        }
+    }
+}
+
+impl ProtocolConfig {
+    pub fn validate(&self) -> Result<(), KademliaError> {
+        if self.k_param == 0 {
+            return Err(KademliaError::InvalidConfig("k_param must be at least 1"));
+        }
+        if self.alpha == 0 {
+            return Err(KademliaError::InvalidConfig("alpha must be at least 1"));
+        }
+        if self.disjoint_paths == 0 || self.disjoint_paths > self.k_param {
+            return Err(KademliaError::InvalidConfig(
+                "disjoint_paths must be between 1 and k_param",
+            ));
+        }
+        if self.ip_limits.bucket_ip == 0
+            || self.ip_limits.bucket_subnet == 0
+            || self.ip_limits.table_ip == 0
+            || self.ip_limits.table_subnet == 0
+        {
+            return Err(KademliaError::InvalidConfig("ip limits must be at least 1"));
+        }
+        if self.max_handshakes == 0 {
+            return Err(KademliaError::InvalidConfig(
+                "max_handshakes must be at least 1",
+            ));
+        }
+        if self.buf_size < fragment::MIN_DATAGRAM || self.buf_size > fragment::MAX_DATAGRAM {
+            return Err(KademliaError::InvalidConfig(
+                "buf_size must be between 512 and 65535 bytes",
+            ));
+        }
+        if self.static_difficulty > puzzle::MAX_DIFFICULTY
+            || self.dynamic_difficulty > puzzle::MAX_DIFFICULTY
+        {
+            return Err(KademliaError::InvalidConfig(
+                "puzzle difficulties must be at most 32 bits",
+            ));
+        }
+        // the background jobs would spin
+        if self.publish_interval.as_millis() == 0
+            || self.republish_interval.as_millis() == 0
+            || self.refresh_interval.as_millis() == 0
+            || self.sweep_interval.as_millis() == 0
+            || self.contacts_save_interval.as_millis() == 0
+            || self.timeout.as_millis() == 0
+        {
+            return Err(KademliaError::InvalidConfig(
+                "background job intervals must be at least 1ms",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Builds a Protocol instance:
+//
+//     let interface = ProtocolBuilder::new()
+//         .bind(ip, port)
+//         .bootstrap(root)
+//         .k_param(8)
+//         .build()
+//         .await?;
+//
+// when no transport is given a UdpTransport is bound to the node address,
+// when no storage is given the pairs are kept in a MemoryStorage,
+// when neither identity nor identity_file is given the node gets a new identity
+#[derive(Debug, Default)]
+pub struct ProtocolBuilder {
+    config: ProtocolConfig,
+    addr: Option<(String, u16)>,
+    bootstrap: Vec<Node>,
+    bootstrap_file: Option<PathBuf>,
+    transport: Option<Arc<dyn Transport>>,
+    codec: Codec,
+    storage: Option<Arc<dyn StorageBackend>>,
+    identity: Option<Identity>,
+    identity_file: Option<PathBuf>,
+}
+
+impl ProtocolBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn config(mut self, config: ProtocolConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn k_param(mut self, k_param: usize) -> Self {
+        self.config.k_param = k_param;
+        self
+    }
+
+    pub fn alpha(mut self, alpha: usize) -> Self {
+        self.config.alpha = alpha;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    pub fn buf_size(mut self, buf_size: usize) -> Self {
+        self.config.buf_size = buf_size;
+        self
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.config.verbose = verbose;
+        self
+    }
+
+    pub fn publish_interval(mut self, interval: Duration) -> Self {
+        self.config.publish_interval = interval;
+        self
+    }
+
+    pub fn publish_lifetime(mut self, lifetime: Duration) -> Self {
+        self.config.publish_lifetime = lifetime;
+        self
+    }
+
+    pub fn republish_interval(mut self, interval: Duration) -> Self {
+        self.config.republish_interval = interval;
+        self
+    }
+
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.config.refresh_interval = interval;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.config.ttl = ttl;
+        self
+    }
+
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.config.sweep_interval = interval;
+        self
+    }
+
+    pub fn handoff_max_keys(mut self, max_keys: usize) -> Self {
+        self.config.handoff_max_keys = max_keys;
+        self
+    }
+
+    pub fn handoff_cooldown(mut self, cooldown: Duration) -> Self {
+        self.config.handoff_cooldown = cooldown;
+        self
+    }
+
+    pub fn handoff_max_stores(mut self, max_stores: usize) -> Self {
+        self.config.handoff_max_stores = max_stores;
+        self
+    }
+
+    pub fn contacts_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.contacts_file = Some(path.into());
+        self
+    }
+
+    pub fn contacts_save_interval(mut self, interval: Duration) -> Self {
+        self.config.contacts_save_interval = interval;
+        self
+    }
+
+    pub fn bootstrap_retries(mut self, retries: u32) -> Self {
+        self.config.bootstrap_retries = retries;
+        self
+    }
+
+    pub fn bootstrap_backoff(mut self, backoff: Duration) -> Self {
+        self.config.bootstrap_backoff = backoff;
+        self
+    }
+
+    pub fn plaintext(mut self, plaintext: bool) -> Self {
+        self.config.plaintext = plaintext;
+        self
+    }
+
+    pub fn max_handshakes(mut self, handshakes: usize) -> Self {
+        self.config.max_handshakes = handshakes;
+        self
+    }
+
+    pub fn static_difficulty(mut self, difficulty: u32) -> Self {
+        self.config.static_difficulty = difficulty;
+        self
+    }
+
+    pub fn dynamic_difficulty(mut self, difficulty: u32) -> Self {
+        self.config.dynamic_difficulty = difficulty;
+        self
+    }
+
+    pub fn disjoint_paths(mut self, d: usize) -> Self {
+        self.config.disjoint_paths = d;
+        self
+    }
+
+    pub fn ip_limits(mut self, limits: IpLimits) -> Self {
+        self.config.ip_limits = limits;
+        self
+    }
+
+    // address of the node, used to bind the default transport
+    pub fn bind(mut self, ip: String, port: u16) -> Self {
+        self.addr = Some((ip, port));
+        self
+    }
+
+    // adds a node we already know in the network, can be called multiple times
+    pub fn bootstrap(mut self, node: Node) -> Self {
+        self.bootstrap.push(node);
+        self
+    }
+
+    // adds the bootstrap nodes listed in a seed file, see utils::read_seed_file
+    pub fn bootstrap_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.bootstrap_file = Some(path.into());
+        self
+    }
+
+    // sends messages through the given transport, which must already be bound to the node address
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    // keeps the stored pairs in the given backend, e.g. a FileStorage to survive restarts
+    pub fn storage(mut self, storage: Arc<dyn StorageBackend>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    // keypair of the node, its id stays the same whatever address the node is bound to.
+    // The id must solve the static puzzle, see Identity::generate_with
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    // keeps the keypair in a file so that the id survives restarts, see Identity::load.
+    // Ignored when an identity is given
+    pub fn identity_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.identity_file = Some(path.into());
+        self
+    }
+
+    pub async fn build(self) -> Result<Protocol, KademliaError> {
+        self.config.validate()?;
+
+        let (ip, port) = self.addr.ok_or_else(|| {
+            KademliaError::Bind(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no bind address given",
+            ))
+        })?;
+        let identity = match (self.identity, &self.identity_file) {
+            (Some(identity), _) => identity,
+            (None, Some(path)) => Identity::load(path, self.config.static_difficulty)
+                .map_err(KademliaError::IdentityFile)?,
+            (None, None) => Identity::generate_with(self.config.static_difficulty),
+        };
+        if !puzzle::solves_static(&identity.id(), self.config.static_difficulty) {
+            return Err(KademliaError::InvalidConfig(
+                "the identity doesn't solve the static puzzle",
+            ));
+        }
+        let node = Node::with_id(ip, port, identity.id());
+
+        let mut bootstrap = self.bootstrap;
+        if let Some(path) = &self.bootstrap_file {
+            bootstrap.extend(utils::read_seed_file(path).map_err(KademliaError::SeedFile)?);
+        }
+
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => Arc::new(
+                UdpTransport::bind(&node.get_addr())
+                    .await
+                    .map_err(KademliaError::Bind)?,
+            ),
+        };
+
+        let storage = self
+            .storage
+            .unwrap_or_else(|| Arc::new(MemoryStorage::new()));
+
+        Protocol::start(
+            node,
+            identity,
+            bootstrap,
+            transport,
+            self.codec,
+            self.config,
+            storage,
+        )
+        .await
+    }
+}
@@ -0,0 +1,78 @@
+use super::lookup::LookupStats;
+use super::network::NetworkError;
+use super::node::Node;
+
+use std::fmt::{Display, Error, Formatter};
+use std::io;
+
+#[derive(Debug)]
+pub enum KademliaError {
+    // the transport couldn't be bound, e.g. the port is already taken
+    Bind(io::Error),
+    InvalidConfig(&'static str),
+    // the seed file given to ProtocolBuilder::bootstrap_file couldn't be read
+    SeedFile(io::Error),
+    // the file given to ProtocolBuilder::identity_file couldn't be read or created
+    IdentityFile(io::Error),
+    // none of the bootstrap nodes and saved contacts answered, the node would be isolated
+    BootstrapFailed(Vec<Node>),
+    Network(NetworkError),
+    // the contacted node didn't answer in time
+    Timeout(Node),
+    // the contacted node answered with a different kind of Response, or under another id
+    UnexpectedResponse(Node),
+    // nobody answered during the lookup
+    Unreachable(LookupStats),
+    // the lookup went fine but no node holds the key
+    NotFound(LookupStats),
+    // none of the replicas acknowledged the Store
+    PutFailed {
+        acked: usize,
+        replicas: usize,
+        lookup: LookupStats,
+    },
+}
+
+impl Display for KademliaError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            KademliaError::Bind(e) => write!(f, "unable to bind transport: {}", e),
+            KademliaError::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            KademliaError::SeedFile(e) => write!(f, "unable to read seed file: {}", e),
+            KademliaError::IdentityFile(e) => write!(f, "unable to load identity: {}", e),
+            KademliaError::BootstrapFailed(nodes) => {
+                write!(f, "none of the {} known nodes answered", nodes.len())
+            }
+            KademliaError::Network(e) => write!(f, "network error: {}", e),
+            KademliaError::Timeout(n) => write!(f, "{} didn't answer in time", n.get_addr()),
+            KademliaError::UnexpectedResponse(n) => {
+                write!(f, "{} answered with an unexpected response", n.get_addr())
+            }
+            KademliaError::Unreachable(stats) => {
+                write!(f, "no node answered ({} timed out)", stats.timed_out)
+            }
+            KademliaError::NotFound(stats) => write!(
+                f,
+                "key not found ({} nodes answered, {} timed out, converged: {})",
+                stats.responded, stats.timed_out, stats.converged
+            ),
+            KademliaError::PutFailed {
+                acked,
+                replicas,
+                lookup,
+            } => write!(
+                f,
+                "{} out of {} replicas acknowledged the store ({} timed out during lookup)",
+                acked, replicas, lookup.timed_out
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KademliaError {}
+
+impl From<NetworkError> for KademliaError {
+    fn from(e: NetworkError) -> Self {
+        KademliaError::Network(e)
+    }
+}
@@ -0,0 +1,254 @@
+use super::utils;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::{Display, Error, Formatter};
+use std::time::{Duration, Instant};
+
+/*
+    Messages larger than a single datagram are split into numbered fragments:
+
+        [FRAGMENT_MAGIC][msg_id: u64][index: u16][count: u16][chunk...]
+
+    (integers are little endian). Whole messages never start with FRAGMENT_MAGIC
+    (see codec.rs), so both kinds of datagrams can share the same socket.
+*/
+
+const FRAGMENT_MAGIC: u8 = 0xF5;
+
+pub const HEADER_LEN: usize = 1 + 8 + 2 + 2;
+
+// Bounds of ProtocolConfig::buf_size. Nodes may send smaller datagrams than their peers,
+// so the receive buffer is always MAX_DATAGRAM bytes and the reassembler expects as
+// many fragments as a sender using MIN_DATAGRAM can produce
+pub const MIN_DATAGRAM: usize = 512;
+pub const MAX_DATAGRAM: usize = 65535;
+
+// upper bound for a reassembled message, anything larger is refused on both ends
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// Incomplete messages kept at the same time, in total and per source IP, and the bytes they
+// can hold. When a limit is hit the oldest message of the source using the most is
+// dropped, so a single peer can only push out its own messages
+pub const MAX_REASSEMBLIES: usize = 64;
+pub const MAX_REASSEMBLIES_PER_SOURCE: usize = 8;
+pub const MAX_REASSEMBLY_BYTES: usize = 4 * MAX_MESSAGE_SIZE;
+pub const MAX_REASSEMBLY_BYTES_PER_SOURCE: usize = 2 * MAX_MESSAGE_SIZE;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FragmentError {
+    // header too short or index/count out of range
+    Malformed,
+    // the reassembled message would exceed MAX_MESSAGE_SIZE
+    TooLarge,
+    // fragments of the same message disagree on the number of fragments
+    Inconsistent,
+    // the message was dropped to stay within the reassembly limits
+    Evicted,
+}
+
+#[derive(Debug)]
+struct Partial {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+#[derive(Debug)]
+pub struct Reassembler {
+    partials: HashMap<(String, u64), Partial>,
+    timeout: Duration,
+    max_fragments: usize,
+}
+
+impl Display for FragmentError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            FragmentError::Malformed => write!(f, "malformed fragment"),
+            FragmentError::TooLarge => write!(f, "fragmented message too large"),
+            FragmentError::Inconsistent => write!(f, "inconsistent fragment count"),
+            FragmentError::Evicted => write!(f, "too many incomplete messages"),
+        }
+    }
+}
+
+impl std::error::Error for FragmentError {}
+
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&FRAGMENT_MAGIC)
+}
+
+// splits a payload in datagrams of at most max_datagram bytes, None if the payload is too big
+pub fn fragment(msg_id: u64, payload: &[u8], max_datagram: usize) -> Option<Vec<Vec<u8>>> {
+    let chunk_len = max_datagram.checked_sub(HEADER_LEN)?;
+    if payload.len() > MAX_MESSAGE_SIZE || chunk_len == 0 {
+        return None;
+    }
+
+    let chunks: Vec<&[u8]> = payload.chunks(chunk_len).collect();
+    let count: u16 = chunks.len().try_into().ok()?;
+
+    let mut ret = Vec::with_capacity(chunks.len());
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let mut datagram = Vec::with_capacity(HEADER_LEN + chunk.len());
+        datagram.push(FRAGMENT_MAGIC);
+        datagram.extend_from_slice(&msg_id.to_le_bytes());
+        datagram.extend_from_slice(&(index as u16).to_le_bytes());
+        datagram.extend_from_slice(&count.to_le_bytes());
+        datagram.extend_from_slice(chunk);
+
+        ret.push(datagram);
+    }
+
+    Some(ret)
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            partials: HashMap::new(),
+            timeout,
+            // the most fragments a sender using the smallest datagrams can produce
+            max_fragments: MAX_MESSAGE_SIZE / (MIN_DATAGRAM - HEADER_LEN) + 1,
+        }
+    }
+
+    // number of messages waiting for more fragments
+    pub fn pending(&self) -> usize {
+        self.partials.len()
+    }
+
+    // feeds a fragment coming from src, returns the whole message once every fragment arrived
+    pub fn accept(&mut self, src: &str, datagram: &[u8]) -> Result<Option<Vec<u8>>, FragmentError> {
+        self.expire();
+
+        if !is_fragment(datagram) || datagram.len() < HEADER_LEN {
+            return Err(FragmentError::Malformed);
+        }
+
+        let msg_id = u64::from_le_bytes(datagram[1..9].try_into().unwrap_or_default());
+        let index = u16::from_le_bytes(datagram[9..11].try_into().unwrap_or_default()) as usize;
+        let count = u16::from_le_bytes(datagram[11..13].try_into().unwrap_or_default()) as usize;
+        let chunk = &datagram[HEADER_LEN..];
+
+        if count == 0 || index >= count {
+            return Err(FragmentError::Malformed);
+        }
+        if count > self.max_fragments {
+            return Err(FragmentError::TooLarge);
+        }
+
+        let id = (src.to_string(), msg_id);
+        let ip = source_ip(src);
+        if !self.partials.contains_key(&id) {
+            if self.usage(&ip).0 >= MAX_REASSEMBLIES_PER_SOURCE {
+                self.evict_oldest(&ip);
+            }
+            if self.partials.len() >= MAX_REASSEMBLIES {
+                let src = self.heaviest(|(count, _)| count);
+                self.evict_oldest(&src);
+            }
+
+            self.partials.insert(
+                id.clone(),
+                Partial {
+                    chunks: vec![None; count],
+                    received: 0,
+                    size: 0,
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        let partial = match self.partials.get_mut(&id) {
+            Some(partial) => partial,
+            None => return Ok(None),
+        };
+        if partial.chunks.len() != count {
+            // the whole message is garbage
+            self.partials.remove(&id);
+            return Err(FragmentError::Inconsistent);
+        }
+
+        if partial.chunks[index].is_none() {
+            partial.size += chunk.len();
+            partial.received += 1;
+            partial.chunks[index] = Some(chunk.to_vec());
+        }
+
+        if partial.size > MAX_MESSAGE_SIZE {
+            self.partials.remove(&id);
+            return Err(FragmentError::TooLarge);
+        }
+
+        while self.usage(&ip).1 > MAX_REASSEMBLY_BYTES_PER_SOURCE {
+            self.evict_oldest(&ip);
+        }
+        while self.partials.values().map(|p| p.size).sum::<usize>() > MAX_REASSEMBLY_BYTES {
+            let src = self.heaviest(|(_, bytes)| bytes);
+            self.evict_oldest(&src);
+        }
+        let partial = match self.partials.get(&id) {
+            Some(partial) => partial,
+            None => return Err(FragmentError::Evicted),
+        };
+
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let mut message = Vec::with_capacity(partial.size);
+        if let Some(partial) = self.partials.remove(&id) {
+            for chunk in partial.chunks.into_iter().flatten() {
+                message.extend_from_slice(&chunk);
+            }
+        }
+
+        Ok(Some(message))
+    }
+
+    // drops the messages whose fragments stopped coming, called on every fragment and
+    // periodically by Protocol, since a silent peer sends no fragment to trigger it
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        self.partials.retain(|_, p| p.started.elapsed() < timeout);
+    }
+
+    // incomplete messages and bytes held for the senders on ip
+    fn usage(&self, ip: &str) -> (usize, usize) {
+        self.partials
+            .iter()
+            .filter(|((from, _), _)| source_ip(from) == ip)
+            .fold((0, 0), |(count, bytes), (_, p)| (count + 1, bytes + p.size))
+    }
+
+    // the source IP using the most of what metric picks out of its usage
+    fn heaviest(&self, metric: fn((usize, usize)) -> usize) -> String {
+        self.partials
+            .keys()
+            .map(|(from, _)| source_ip(from))
+            .max_by_key(|ip| metric(self.usage(ip)))
+            .unwrap_or_default()
+    }
+
+    fn evict_oldest(&mut self, ip: &str) {
+        let oldest = self
+            .partials
+            .iter()
+            .filter(|((from, _), _)| source_ip(from) == ip)
+            .min_by_key(|(_, p)| p.started)
+            .map(|(id, _)| id.clone());
+
+        if let Some(id) = oldest {
+            self.partials.remove(&id);
+        }
+    }
+}
+
+// sources are told apart by IP, a peer can send from as many ports as it likes
+fn source_ip(src: &str) -> String {
+    utils::parse_addr(src)
+        .map(|(ip, _)| ip)
+        .unwrap_or_else(|| src.to_string())
+}
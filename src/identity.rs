@@ -0,0 +1,132 @@
+use super::key::Key;
+use super::puzzle;
+use super::utils;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use std::convert::TryFrom;
+use std::fmt::{Debug, Error, Formatter};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+// The Ed25519 keypair of a node, its id is the sha256 of the public key
+// so that nobody can claim an id without holding the secret key
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self::from_secret(rand::random())
+    }
+
+    // generates keypairs until the id solves the static puzzle, see puzzle.rs
+    pub fn generate_with(static_difficulty: u32) -> Self {
+        loop {
+            let identity = Self::generate();
+            if puzzle::solves_static(&identity.id(), static_difficulty) {
+                return identity;
+            }
+        }
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    // Reads the secret key saved in path as hex, a new identity solving the static puzzle
+    // is generated and saved there (readable by the owner only) when the file doesn't exist yet.
+    // On unix a file that the group or others can access is refused
+    pub fn load(path: &Path, static_difficulty: u32) -> io::Result<Self> {
+        let mut file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate_with(static_difficulty);
+                utils::write_secret(path, hex::encode(identity.secret()).as_bytes())?;
+                return Ok(identity);
+            }
+            Err(e) => return Err(e),
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = file.metadata()?.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "{} has mode {:o}, the secret key must be private (0600)",
+                        path.display(),
+                        mode
+                    ),
+                ));
+            }
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        let mut secret = [0u8; 32];
+        hex::decode_to_slice(contents.trim(), &mut secret)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Self::from_secret(secret))
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn id(&self) -> Key {
+        id_of(&self.public_key())
+    }
+
+    pub fn sign(&self, bytes: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(bytes).to_bytes().to_vec()
+    }
+}
+
+// the secret key is never printed
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "Identity({:?})", self.id())
+    }
+}
+
+pub fn id_of(public_key: &[u8; PUBLIC_KEY_LEN]) -> Key {
+    let mut id = [0u8; super::KEY_LEN];
+    id.copy_from_slice(&Sha256::digest(public_key));
+
+    Key(id)
+}
+
+// true when signature is a valid signature of bytes by public_key, and public_key belongs to id
+pub fn verify(id: &Key, public_key: &[u8; PUBLIC_KEY_LEN], bytes: &[u8], signature: &[u8]) -> bool {
+    if id_of(public_key) != *id {
+        return false;
+    }
+
+    let signature = match <[u8; SIGNATURE_LEN]>::try_from(signature) {
+        Ok(signature) => Signature::from_bytes(&signature),
+        // unsigned or truncated
+        Err(_) => return false,
+    };
+
+    match VerifyingKey::from_bytes(public_key) {
+        Ok(key) => key.verify(bytes, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
@@ -7,6 +7,11 @@ use std::fmt::{Binary, Debug, Error, Formatter};
 pub struct Key(pub [u8; KEY_LEN]);
 
 impl Key {
+    // a random key, e.g. a lookup target or a request token
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
     pub fn new(input: String) -> Self {
         let mut hasher = Sha256::new();
         hasher.update(input.as_bytes());
@@ -23,6 +28,19 @@ impl Key {
     }
 }
 
+// strings are hashed, use Key(bytes) for raw keys
+impl From<&str> for Key {
+    fn from(input: &str) -> Self {
+        Self::new(input.to_string())
+    }
+}
+
+impl From<String> for Key {
+    fn from(input: String) -> Self {
+        Self::new(input)
+    }
+}
+
 impl Debug for Key {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         for x in &self.0 {
@@ -38,8 +56,8 @@ pub struct Distance(pub [u8; KEY_LEN]);
 impl Distance {
     pub fn new(k1: &Key, k2: &Key) -> Distance {
         let mut ret = [0; KEY_LEN];
-        for i in 0..KEY_LEN {
-            ret[i] = k1.0[i] ^ k2.0[i];
+        for (i, byte) in ret.iter_mut().enumerate() {
+            *byte = k1.0[i] ^ k2.0[i];
         }
 
         Self(ret)
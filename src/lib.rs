@@ -1,8 +1,19 @@
+pub mod codec;
+pub mod config;
+pub mod error;
+pub mod fragment;
+pub mod identity;
 pub mod key;
+pub mod lookup;
 pub mod network;
 pub mod node;
 pub mod protocol;
+pub mod puzzle;
 pub mod routing;
+pub mod session;
+pub mod simulator;
+pub mod storage;
+pub mod transport;
 pub mod utils;
 
 // 256 bits --> 32 bytes
@@ -12,6 +23,8 @@ const KEY_LEN: usize = 32;
 // 32*8 --> 256
 const N_BUCKETS: usize = KEY_LEN * 8;
 
+// defaults of config::ProtocolConfig
+
 // number entries in a list
 const K_PARAM: usize = 20;
 
@@ -28,11 +41,39 @@ const VERBOSE: bool = false;
 
 #[cfg(test)]
 mod tests {
-    use super::key::Distance;
+    use super::codec::Codec;
+    use super::config::ProtocolBuilder;
+    use super::error::KademliaError;
+    use super::fragment::{self, FragmentError, Reassembler};
+    use super::identity::Identity;
+    use super::key::{Distance, Key};
+    use super::lookup::DisjointLookup;
+    use super::network::{Message, Request, Response, RpcMessage};
     use super::node::Node;
     use super::protocol::Protocol;
-    use super::routing::NodeAndDistance;
+    use super::puzzle;
+    use super::routing::{self, IpLimits, NodeAndDistance, RoutingTable};
+    use super::session::{self, SessionError, Sessions};
+    use super::simulator::{SimConfig, Simulator};
+    use super::storage::{self, FileStorage, StorageBackend, StoreEntry};
+    use super::transport::{MemoryNetwork, Transport};
     use super::utils;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    // builder for a node on the in-memory network, the built node has its own id (not node.id).
+    // Test nodes share a few IPs, so the IP limits are lifted
+    fn memory_node(network: &MemoryNetwork, node: &Node, codec: Codec) -> ProtocolBuilder {
+        let transport = network.bind(&node.get_addr()).unwrap();
+
+        ProtocolBuilder::new()
+            .bind(node.ip.clone(), node.port)
+            .transport(Arc::new(transport))
+            .codec(codec)
+            .ip_limits(IpLimits::disabled())
+            .max_handshakes(usize::MAX)
+    }
 
     #[test]
     fn compare_distance() {
@@ -40,33 +81,1840 @@ mod tests {
         let node1 = Node::new(utils::get_local_ip().unwrap(), 1336);
 
         let dist = Distance::new(&node0.id, &node1.id);
-        let nd0 = NodeAndDistance(node0.clone(), dist.clone());
-        let nd1 = NodeAndDistance(node1.clone(), dist.clone());
+        let nd0 = NodeAndDistance(node0.clone(), dist);
+        let nd1 = NodeAndDistance(node1.clone(), dist);
 
         assert_eq!(nd0, nd1);
     }
 
     #[test]
-    fn main_test() {
+    fn replacement_cache() {
+        let node = Node::new("10.0.0.7".to_owned(), 7000);
+        let mut routes = RoutingTable::new(node, 2);
+
+        // filling buckets until one overflows
+        let mut contacts = (7001..).map(|port| Node::new("10.0.0.7".to_owned(), port));
+        let (lrs, newcomer) = loop {
+            let contact = contacts.next().unwrap();
+            if let Some(lrs) = routes.try_update(contact.clone()) {
+                break (lrs, contact);
+            }
+        };
+        assert!(!routes.contains(&newcomer.id));
+
+        // a single probe per bucket at a time, the others just wait in the cache
+        let bucket = routes
+            .kbuckets
+            .iter()
+            .position(|kb| kb.probing.is_some())
+            .unwrap();
+        while routes.kbuckets[bucket].replacements.len() < 2 {
+            let probe = routes.try_update(contacts.next().unwrap());
+            assert_ne!(probe.map(|n| n.id), Some(lrs.id.clone()));
+        }
+        let late = routes.kbuckets[bucket].replacements[1].clone();
+
+        // the most recent replacement takes the place of the dead contact
+        routes.ping_result(&lrs, false);
+        assert!(!routes.contains(&lrs.id));
+        assert!(routes.contains(&late.id));
+        assert!(routes.kbuckets[bucket].probing.is_none());
+
+        // and the next one is promoted when a contact goes away
+        let evicted = routes.kbuckets[bucket].nodes[0].clone();
+        routes.remove(&evicted);
+        assert!(routes.contains(&newcomer.id));
+        assert!(routes.kbuckets[bucket].replacements.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bucket_refresh() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.8".to_owned(), 8000);
+        let node1 = Node::new("10.0.0.8".to_owned(), 8001);
+
+        let interface0 = memory_node(&network, &node0, Codec::Binary)
+            .build()
+            .await
+            .unwrap();
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .bootstrap(node0)
+            .refresh_interval(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+
+        {
+            let routes = interface1.routes.lock().unwrap();
+            for i in [0, 7, 8, 100, 254, 255].iter() {
+                let id = routes.random_id_in_bucket(*i);
+                assert_eq!(routes.get_lookup_bucket_index(&id), *i);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        assert!(interface1.stats.bucket_refreshes.load(Ordering::Relaxed) > 0);
+        // the default interval is one hour
+        assert_eq!(interface0.refresh_buckets().await, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expiration() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.9".to_owned(), 9000);
+        let node1 = Node::new("10.0.0.9".to_owned(), 9001);
+
+        let interface0 = memory_node(&network, &node0, Codec::Binary)
+            .sweep_interval(Duration::from_millis(50))
+            .build()
+            .await
+            .unwrap();
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .bootstrap(node0)
+            .ttl(Duration::from_millis(300))
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            interface1
+                .put("short_lived".to_owned(), "v".to_owned())
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(b"v", &interface0.get("short_lived").await.unwrap()[..]);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        // interface1 didn't sweep yet, but it doesn't hand out expired values
+        assert!(interface1.store.get(&Key::from("short_lived")).is_some());
+        assert!(matches!(
+            interface0.get("short_lived".to_owned()).await,
+            Err(KademliaError::NotFound(_))
+        ));
+        assert!(interface0.store.entries().is_empty());
+
+        // a peer can't make us keep a pair longer than our own ttl, nor overflow its expiry
+        let forever = Key::from("forever");
+        interface1
+            .store(
+                interface0.node.clone(),
+                forever.clone(),
+                b"v".to_vec(),
+                Duration::MAX,
+            )
+            .await
+            .unwrap();
+        let entry = interface0.store.get(&forever).unwrap();
+        assert!(entry.expires_at <= SystemTime::now() + interface0.config.ttl);
+        assert_eq!(b"v", &interface1.get(forever).await.unwrap()[..]);
+        // any ttl is clamped to storage::MAX_TTL
+        let before = SystemTime::now();
+        let entry = StoreEntry::new(Vec::new(), Duration::MAX);
+        assert!(entry.expires_at >= before + storage::MAX_TTL);
+        assert!(entry.expires_at <= SystemTime::now() + storage::MAX_TTL);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handoff() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.10".to_owned(), 1000);
+        let node1 = Node::new("10.0.0.10".to_owned(), 1001);
+        let node2 = Node::new("10.0.0.10".to_owned(), 1002);
+
+        let interface0 = memory_node(&network, &node0, Codec::Binary)
+            .build()
+            .await
+            .unwrap();
+        let keys: Vec<String> = (0..40).map(|i| format!("handoff_{}", i)).collect();
+        for k in &keys {
+            assert_eq!(interface0.put(k.clone(), "v".to_owned()).await.unwrap(), 1);
+        }
+        let interface2 = memory_node(&network, &node2, Codec::Binary)
+            .bootstrap(node0.clone())
+            .build()
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let before = interface0.stats.handed_off.load(Ordering::Relaxed);
+
+        // node1 joins through node0, which hands over the keys node1 is closer to,
+        // unless node2 is closer than node0 and keeps them instead
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .bootstrap(node0.clone())
+            .build()
+            .await
+            .unwrap();
+        let closer: Vec<&String> = keys
+            .iter()
+            .filter(|k| {
+                let key = Key::new(k.to_string());
+                let own = Distance::new(&interface0.node.id, &key);
+                Distance::new(&interface1.node.id, &key) < own
+                    && Distance::new(&interface2.node.id, &key) >= own
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let handed_off = interface0.stats.handed_off.load(Ordering::Relaxed);
+        assert_eq!(handed_off - before, closer.len() as u64);
+        assert!(closer
+            .iter()
+            .all(|k| interface1.store.get(&Key::from(k.as_str())).is_some()));
+
+        // node1 is in cooldown, seeing it again as a new contact sends nothing
+        interface0.routes.lock().unwrap().remove(&interface1.node);
+        interface0.ping(node1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            interface0.stats.handed_off.load(Ordering::Relaxed),
+            handed_off
+        );
+
+        // past handoff_max_stores, new contacts get nothing until the period ends
+        let node3 = Node::new("10.0.0.10".to_owned(), 1003);
+        let interface3 = memory_node(&network, &node3, Codec::Binary)
+            .handoff_max_stores(1)
+            .build()
+            .await
+            .unwrap();
+        for k in &keys {
+            assert_eq!(interface3.put(k.clone(), "v".to_owned()).await.unwrap(), 1);
+        }
+        for port in 1004..1006 {
+            memory_node(
+                &network,
+                &Node::new("10.0.0.10".to_owned(), port),
+                Codec::Binary,
+            )
+            .bootstrap(node3.clone())
+            .build()
+            .await
+            .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(interface3.stats.handed_off.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn republish() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.11".to_owned(), 1100);
+        let node1 = Node::new("10.0.0.11".to_owned(), 1101);
+
+        let interface0 = memory_node(&network, &node0, Codec::Binary)
+            .republish_interval(Duration::from_millis(400))
+            .build()
+            .await
+            .unwrap();
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .bootstrap(node0)
+            .publish_interval(Duration::from_millis(150))
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            interface1
+                .put("republished".to_owned(), "v".to_owned())
+                .await
+                .unwrap(),
+            2
+        );
+        let key = Key::from("republished");
+        assert!(interface1.store.get(&key).unwrap().publisher);
+        assert!(!interface0.store.get(&key).unwrap().publisher);
+        // nothing is due yet
+        assert_eq!(interface0.republish().await, 0);
+        assert_eq!(interface1.republish().await, 0);
+
+        // the publisher keeps republishing, so the replica never needs to
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(interface1.stats.republished.load(Ordering::Relaxed) >= 2);
+        assert_eq!(interface0.stats.republished.load(Ordering::Relaxed), 0);
+        assert!(interface1.store.get(&key).unwrap().publisher);
+
+        // once unpublished it's a plain replica, which gets swept when it expires
+        assert!(interface1.unpublish("republished"));
+        assert!(!interface1.unpublish("republished"));
+        assert!(!interface1.store.get(&key).unwrap().publisher);
+        let node2 = Node::new("10.0.0.11".to_owned(), 1102);
+        let interface2 = memory_node(&network, &node2, Codec::Binary)
+            .ttl(Duration::from_millis(300))
+            .publish_lifetime(Duration::from_millis(300))
+            .build()
+            .await
+            .unwrap();
+        interface2.put("short lived", "v").await.unwrap();
+        let key = Key::from("short lived");
+        assert!(interface2.store.get(&key).unwrap().publisher);
+        assert_eq!(interface2.sweep(), 0);
+        // past publish_lifetime, publishing stops and the pair expires
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(interface2.sweep(), 1);
+        assert!(interface2.store.get(&key).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_storage() {
+        let path = std::env::temp_dir().join(format!("kademlia_store_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        let network = MemoryNetwork::new();
+        let node = Node::new("10.0.0.12".to_owned(), 1200);
+        let storage = Arc::new(FileStorage::open(&path).unwrap());
+        let interface = memory_node(&network, &node, Codec::Binary)
+            .storage(storage.clone())
+            .build()
+            .await
+            .unwrap();
+        interface.put("persisted", "v").await.unwrap();
+        interface.put("deleted", "v").await.unwrap();
+        interface.store.delete(&Key::from("deleted")).unwrap();
+        // shutdown flushes the store
+        interface.shutdown(false).await;
+
+        // a restarted node finds what it stored, publisher included
+        let storage = FileStorage::open(&path).unwrap();
+        let entry = storage.get(&Key::from("persisted")).unwrap();
+        assert_eq!(entry.value, b"v");
+        assert!(entry.publisher);
+        assert!(storage.get(&Key::from("deleted")).is_none());
+        // one file per pair
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 1);
+
+        // a leftover temporary file from a crash doesn't affect the saved pairs
+        let tmp = path.join(format!("{}.tmp", hex::encode(Key::from("other").0)));
+        std::fs::write(&tmp, b"garbage").unwrap();
+        assert_eq!(FileStorage::open(&path).unwrap().entries().len(), 1);
+        storage
+            .put(
+                Key::from("other"),
+                StoreEntry::new(vec![1, 2], Duration::from_secs(1)),
+            )
+            .unwrap();
+        storage.flush().unwrap();
+        assert_eq!(FileStorage::open(&path).unwrap().entries().len(), 2);
+
+        // a write that failed is tried again by the next flush
+        std::fs::remove_dir_all(&path).unwrap();
+        storage
+            .put(
+                Key::from("retried"),
+                StoreEntry::new(vec![3], Duration::from_secs(60)),
+            )
+            .unwrap();
+        assert!(storage.flush().is_err());
+        std::fs::create_dir_all(&path).unwrap();
+        storage.flush().unwrap();
+        let restarted = FileStorage::open(&path).unwrap();
+        assert_eq!(restarted.get(&Key::from("retried")).unwrap().value, vec![3]);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn warm_restart() {
+        let path = std::env::temp_dir().join(format!("kademlia_contacts_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let node0 = Node::new("10.0.0.13".to_owned(), 1300);
+        let node1 = Node::new("10.0.0.13".to_owned(), 1301);
+        let node2 = Node::new("10.0.0.13".to_owned(), 1302);
+
+        let identity0 = Identity::generate();
+        let network = MemoryNetwork::new();
+        let _interface0 = memory_node(&network, &node0, Codec::Binary)
+            .identity(identity0.clone())
+            .build()
+            .await
+            .unwrap();
+        let interface2 = memory_node(&network, &node2, Codec::Binary)
+            .bootstrap(node0.clone())
+            .build()
+            .await
+            .unwrap();
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .bootstrap(node2.clone())
+            .contacts_file(&path)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(interface1.save_contacts().await.unwrap(), 2);
+        // concurrent saves take turns on the temporary file
+        let saves: Vec<_> = (0..8)
+            .map(|_| {
+                let interface1 = interface1.clone();
+                tokio::spawn(async move { interface1.save_contacts().await })
+            })
+            .collect();
+        for save in saves {
+            assert_eq!(save.await.unwrap().unwrap(), 2);
+        }
+        let id2 = interface2.node.id.clone();
+        drop(interface2);
+
+        // node1 restarts without bootstrap nodes in a network where node2 is gone
+        let network = MemoryNetwork::new();
+        let _interface0 = memory_node(&network, &node0, Codec::Binary)
+            .identity(identity0.clone())
+            .build()
+            .await
+            .unwrap();
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .contacts_file(&path)
+            .timeout(Duration::from_millis(200))
+            .build()
+            .await
+            .unwrap();
+
+        {
+            let routes = interface1.routes.lock().unwrap();
+            assert!(routes.contains(&identity0.id()));
+            assert!(!routes.contains(&id2));
+        }
+        assert_eq!(interface1.save_contacts().await.unwrap(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stable_identity() {
+        let path = std::env::temp_dir().join(format!("kademlia_identity_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let network = MemoryNetwork::new();
+        let addr0 = Node::new("10.0.0.16".to_owned(), 1600);
+        let interface0 = ProtocolBuilder::new()
+            .bind(addr0.ip.clone(), addr0.port)
+            .transport(Arc::new(network.bind(&addr0.get_addr()).unwrap()))
+            .identity_file(&path)
+            .build()
+            .await
+            .unwrap();
+        let id = interface0.node.id.clone();
+        assert_ne!(id, addr0.id);
+        interface0.shutdown(false).await;
+
+        // same id on another address
+        let addr1 = Node::new("10.0.0.16".to_owned(), 1601);
+        let interface0 = ProtocolBuilder::new()
+            .bind(addr1.ip.clone(), addr1.port)
+            .transport(Arc::new(network.bind(&addr1.get_addr()).unwrap()))
+            .identity_file(&path)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(interface0.node.id, id);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // the bootstrap node is known by address only, its id comes with its response
+        let interface1 = memory_node(
+            &network,
+            &Node::new("10.0.0.16".to_owned(), 1602),
+            Codec::Binary,
+        )
+        .bootstrap(addr1.clone())
+        .build()
+        .await
+        .unwrap();
+        {
+            let routes = interface1.routes.lock().unwrap();
+            assert!(routes.contains(&id));
+            assert!(!routes.contains(&addr1.id));
+        }
+        assert!(interface0
+            .routes
+            .lock()
+            .unwrap()
+            .contains(&interface1.node.id));
+
+        let load = |port: u16| {
+            ProtocolBuilder::new()
+                .bind("10.0.0.16".to_owned(), port)
+                .transport(Arc::new(
+                    network.bind(&format!("10.0.0.16:{}", port)).unwrap(),
+                ))
+                .identity_file(&path)
+                .build()
+        };
+        utils::write_secret(&path, b"not hex").unwrap();
+        let res = load(1603).await;
+        assert!(
+            matches!(res, Err(KademliaError::IdentityFile(ref e)) if e.kind() == std::io::ErrorKind::InvalidData)
+        );
+
+        // a key others can read isn't used
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            let res = load(1604).await;
+            assert!(
+                matches!(res, Err(KademliaError::IdentityFile(ref e)) if e.kind() == std::io::ErrorKind::PermissionDenied)
+            );
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bootstrap_retry() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.14".to_owned(), 1400);
+        let dead = Node::new("10.0.0.14".to_owned(), 1499);
+
+        let res = memory_node(
+            &network,
+            &Node::new("10.0.0.14".to_owned(), 1401),
+            Codec::Binary,
+        )
+        .bootstrap(dead.clone())
+        .bootstrap_retries(1)
+        .bootstrap_backoff(Duration::from_millis(50))
+        .timeout(Duration::from_millis(100))
+        .build()
+        .await;
+        assert!(
+            matches!(res, Err(KademliaError::BootstrapFailed(ref nodes)) if nodes.len() == 1 && nodes[0] == dead)
+        );
+
+        let path = std::env::temp_dir().join(format!("kademlia_seeds_{}", std::process::id()));
+        std::fs::write(&path, "# seeds\n10.0.0.14:1499\n\n10.0.0.14:1400\n").unwrap();
+
+        // node0 comes up while node2 is still retrying
+        let identity0 = Identity::generate();
+        let id0 = identity0.id();
+        let network_clone = network.clone();
+        let node0_clone = node0.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            memory_node(&network_clone, &node0_clone, Codec::Binary)
+                .identity(identity0)
+                .build()
+                .await
+                .unwrap();
+        });
+        let interface2 = memory_node(
+            &network,
+            &Node::new("10.0.0.14".to_owned(), 1402),
+            Codec::Binary,
+        )
+        .bootstrap_file(&path)
+        .bootstrap_retries(3)
+        .bootstrap_backoff(Duration::from_millis(100))
+        .timeout(Duration::from_millis(100))
+        .build()
+        .await
+        .unwrap();
+        {
+            let routes = interface2.routes.lock().unwrap();
+            assert!(routes.contains(&id0));
+            assert!(!routes.contains(&dead.id));
+        }
+
+        // a live seed is enough, the dead one isn't waited for
+        let start = Instant::now();
+        let interface4 = memory_node(
+            &network,
+            &Node::new("10.0.0.14".to_owned(), 1404),
+            Codec::Binary,
+        )
+        .bootstrap(dead.clone())
+        .bootstrap(node0.clone())
+        .bootstrap_retries(3)
+        .bootstrap_backoff(Duration::from_secs(1))
+        .timeout(Duration::from_millis(100))
+        .build()
+        .await
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(interface4.routes.lock().unwrap().contains(&id0));
+        interface4.shutdown(false).await;
+
+        std::fs::write(&path, "10.0.0.14\n").unwrap();
+        let res = memory_node(
+            &network,
+            &Node::new("10.0.0.14".to_owned(), 1403),
+            Codec::Binary,
+        )
+        .bootstrap_file(&path)
+        .build()
+        .await;
+        assert!(matches!(res, Err(KademliaError::SeedFile(_))));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.15".to_owned(), 1500);
+        let node1 = Node::new("10.0.0.15".to_owned(), 1501);
+
+        let interface0 = memory_node(&network, &node0, Codec::Binary)
+            .build()
+            .await
+            .unwrap();
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .bootstrap(node0.clone())
+            .build()
+            .await
+            .unwrap();
+        let key = Key::from("leaving");
+        interface1
+            .store(
+                node1.clone(),
+                key.clone(),
+                b"v".to_vec(),
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+
+        // node1 leaves, handing its pair over to node0
+        interface1.shutdown(true).await;
+        assert!(interface1.tasks.lock().unwrap().is_empty());
+        assert_eq!(interface1.stats.handed_off.load(Ordering::Relaxed), 1);
+        assert_eq!(interface0.store.get(&key).unwrap().value, b"v");
+        assert!(interface1.ping(node0.clone()).await.is_err());
+
+        // the address is free again
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .bootstrap(node0)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(interface1.get("leaving").await.unwrap(), b"v");
+
+        // same for UDP sockets
+        let udp = Protocol::new("127.0.0.1".to_owned(), 1600, None)
+            .await
+            .unwrap();
+        udp.shutdown(false).await;
+        let udp = Protocol::new("127.0.0.1".to_owned(), 1600, None)
+            .await
+            .unwrap();
+        udp.shutdown(false).await;
+    }
+
+    #[test]
+    fn binary_codec() {
+        let target = Key::new("target".to_owned());
+        let entries = (0..super::K_PARAM as u16)
+            .map(|i| {
+                let node = Node::new("192.168.100.100".to_owned(), 10000 + i);
+                let dist = Distance::new(&node.id, &target);
+                NodeAndDistance(node, dist)
+            })
+            .collect();
+
+        let msg = RpcMessage {
+            token: target.clone(),
+            src: "192.168.100.100:9999".to_owned(),
+            src_id: Key::random(),
+            dst: "192.168.100.101:9999".to_owned(),
+            puzzle: Key::random(),
+            public_key: [0; 32],
+            signature: vec![0; 64],
+            msg: Message::Response(Response::FindNode(entries)),
+        };
+
+        let json = Codec::Json.encode(&msg).unwrap();
+        let binary = Codec::Binary.encode(&msg).unwrap();
+        assert!(binary.len() < super::BUF_SIZE);
+        assert!(binary.len() * 3 < json.len());
+
+        for encoded in [json, binary].iter() {
+            let decoded = Codec::decode(encoded).unwrap();
+            assert_eq!(decoded.token, msg.token);
+            match decoded.msg {
+                Message::Response(Response::FindNode(found)) => {
+                    assert_eq!(found.len(), super::K_PARAM)
+                }
+                _ => panic!("decoded the wrong message"),
+            }
+        }
+
+        // values are raw bytes, the binary codec adds only a few bytes to them
+        let value = vec![0xFF; 1024];
+        let store = RpcMessage {
+            token: target.clone(),
+            src: "192.168.100.100:9999".to_owned(),
+            src_id: Key::random(),
+            dst: "192.168.100.101:9999".to_owned(),
+            puzzle: Key::random(),
+            public_key: [0; 32],
+            signature: vec![0; 64],
+            msg: Message::Request(Request::Store(
+                target,
+                value.clone(),
+                Duration::from_secs(1),
+            )),
+        };
+        let binary = Codec::Binary.encode(&store).unwrap();
+        assert!(binary.len() < value.len() + 320);
+        match Codec::decode(&binary).unwrap().msg {
+            Message::Request(Request::Store(_, v, _)) => assert_eq!(v, value),
+            _ => panic!("decoded the wrong message"),
+        }
+    }
+
+    #[test]
+    fn fragments_reassembly() {
+        let payload: Vec<u8> = (0..50_000u32).map(|i| i as u8).collect();
+        let mut datagrams = fragment::fragment(7, &payload, super::BUF_SIZE).unwrap();
+        assert!(datagrams.iter().all(|d| d.len() <= super::BUF_SIZE));
+
+        // order doesn't matter, duplicates and datagrams from other peers are ignored
+        datagrams.reverse();
+        let mut reassembler = Reassembler::new(Duration::from_millis(super::TIMEOUT));
+        let last = datagrams.pop().unwrap();
+        for d in &datagrams {
+            assert_eq!(reassembler.accept("10.0.0.1:1000", d), Ok(None));
+            assert_eq!(reassembler.accept("10.0.0.1:1000", d), Ok(None));
+        }
+        assert_eq!(reassembler.accept("10.0.0.2:1000", &last), Ok(None));
+        assert_eq!(
+            reassembler.accept("10.0.0.1:1000", &last),
+            Ok(Some(payload.clone()))
+        );
+
+        // incomplete messages are forgotten after the timeout
+        let mut reassembler = Reassembler::new(Duration::from_millis(10));
+        reassembler.accept("10.0.0.1:1000", &datagrams[0]).unwrap();
+        assert_eq!(reassembler.pending(), 1);
+        std::thread::sleep(Duration::from_millis(20));
+        reassembler.accept("10.0.0.1:1000", &datagrams[1]).unwrap();
+        assert_eq!(reassembler.pending(), 1);
+        std::thread::sleep(Duration::from_millis(20));
+        reassembler.expire();
+        assert_eq!(reassembler.pending(), 0);
+
+        assert_eq!(
+            reassembler.accept("10.0.0.1:1000", &datagrams[0][..5]),
+            Err(FragmentError::Malformed)
+        );
+
+        // flooding peers only push out their own messages, by count and by bytes
+        let mut reassembler = Reassembler::new(Duration::from_millis(super::TIMEOUT));
+        let mut datagrams = fragment::fragment(7, &payload, super::BUF_SIZE).unwrap();
+        let last = datagrams.pop().unwrap();
+        for d in &datagrams {
+            reassembler.accept("10.0.0.2:1000", d).unwrap();
+        }
+        for msg_id in 0..100 {
+            let flood = fragment::fragment(msg_id, &payload, super::BUF_SIZE).unwrap();
+            reassembler.accept("10.0.0.3:1000", &flood[0]).unwrap();
+        }
+        assert_eq!(
+            reassembler.pending(),
+            1 + fragment::MAX_REASSEMBLIES_PER_SOURCE
+        );
+        // whatever the port they send from
+        for port in 0..100 {
+            let flood = fragment::fragment(port, &payload, super::BUF_SIZE).unwrap();
+            reassembler
+                .accept(&format!("10.0.0.5:{}", port), &flood[0])
+                .unwrap();
+        }
+        assert_eq!(
+            reassembler.pending(),
+            1 + 2 * fragment::MAX_REASSEMBLIES_PER_SOURCE
+        );
+        let big = vec![0u8; fragment::MAX_MESSAGE_SIZE - 1024];
+        for port in 0..10 {
+            let mut flood = fragment::fragment(1, &big, super::BUF_SIZE).unwrap();
+            flood.pop();
+            for d in &flood {
+                let _ = reassembler.accept(&format!("10.0.0.4:{}", port), d);
+            }
+        }
+        assert_eq!(
+            reassembler.accept("10.0.0.2:1000", &last),
+            Ok(Some(payload))
+        );
+    }
+
+    #[test]
+    fn session_handshake() {
+        let initiator = Sessions::new(
+            Arc::new(Identity::generate()),
+            "10.0.0.1:1".to_owned(),
+            usize::MAX,
+        );
+        let responder_identity = Identity::generate();
+        let responder = Sessions::new(
+            Arc::new(responder_identity.clone()),
+            "10.0.0.1:2".to_owned(),
+            usize::MAX,
+        );
+
+        // only the address it was meant for accepts an Init
+        let (_, init, _) = initiator.initiate("10.0.0.1:3", None);
+        assert_eq!(
+            responder.handle_handshake("10.0.0.1:1", &init.unwrap()),
+            Err(SessionError::BadSignature)
+        );
+
+        let (_, init, mut receiver) = initiator.initiate("10.0.0.1:2", None);
+        let accept = responder
+            .handle_handshake("10.0.0.1:1", &init.unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(initiator.handle_handshake("10.0.0.1:2", &accept), Ok(None));
+        let session = receiver.try_recv().unwrap();
+        assert_eq!(session.peer_id, responder_identity.id());
+
+        let sealed = session.seal(b"some secret value");
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        // a sealed message replayed from elsewhere isn't accepted
+        assert_eq!(
+            responder.open("10.0.0.1:4", &sealed).unwrap_err(),
+            SessionError::WrongAddress
+        );
+        let (_, plaintext) = responder.open("10.0.0.1:1", &sealed).unwrap();
+        assert_eq!(plaintext, b"some secret value");
+        // nor twice
+        assert_eq!(
+            responder.open("10.0.0.1:1", &sealed).unwrap_err(),
+            SessionError::Replayed
+        );
+
+        // late messages are fine within the window
+        let late: Vec<Vec<u8>> = (0..10).map(|_| session.seal(b"late")).collect();
+        for sealed in late.iter().rev() {
+            assert!(responder.open("10.0.0.1:1", sealed).is_ok());
+        }
+        for _ in 0..session::REPLAY_WINDOW {
+            session.seal(b"skipped");
+        }
+        let too_late = session.seal(b"too late");
+        for _ in 0..session::REPLAY_WINDOW {
+            let sealed = session.seal(b"newer");
+            assert!(responder.open("10.0.0.1:1", &sealed).is_ok());
+        }
+        assert_eq!(
+            responder.open("10.0.0.1:1", &too_late).unwrap_err(),
+            SessionError::Replayed
+        );
+
+        // replies go through the same session
+        let reply = responder.get("10.0.0.1:1").unwrap().seal(b"reply");
+        assert_eq!(initiator.open("10.0.0.1:2", &reply).unwrap().1, b"reply");
+
+        let mut tampered = session.seal(b"tampered");
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            responder.open("10.0.0.1:1", &tampered).unwrap_err(),
+            SessionError::Decrypt
+        );
+        responder.forget("10.0.0.1:1");
+        assert_eq!(
+            responder.open("10.0.0.1:1", &sealed).unwrap_err(),
+            SessionError::UnknownSession
+        );
+
+        // a replayed Init doesn't replace the session
+        let (_, init, mut receiver) = initiator.initiate("10.0.0.1:2", None);
+        let init = init.unwrap();
+        let accept = responder
+            .handle_handshake("10.0.0.1:1", &init)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            responder.handle_handshake("10.0.0.1:5", &init),
+            Err(SessionError::DuplicateSession)
+        );
+        assert_eq!(responder.get("10.0.0.1:5").map(|s| s.id), None);
+
+        // an Accept from elsewhere leaves the handshake waiting for the real one
+        assert_eq!(
+            initiator.handle_handshake("10.0.0.1:5", &accept),
+            Err(SessionError::UnexpectedAccept)
+        );
+        assert_eq!(initiator.handle_handshake("10.0.0.1:2", &accept), Ok(None));
+        let session = receiver.try_recv().unwrap();
+        assert!(responder.open("10.0.0.1:1", &session.seal(b"used")).is_ok());
+
+        // nor one signed by another node than expected
+        let impostor = Sessions::new(
+            Arc::new(Identity::generate()),
+            "10.0.0.1:2".to_owned(),
+            usize::MAX,
+        );
+        let (_, init, mut receiver) =
+            initiator.initiate("10.0.0.1:2", Some(&responder_identity.id()));
+        let init = init.unwrap();
+        let forged = impostor
+            .handle_handshake("10.0.0.1:1", &init)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            initiator.handle_handshake("10.0.0.1:2", &forged),
+            Err(SessionError::UnexpectedPeer)
+        );
+        let accept = responder
+            .handle_handshake("10.0.0.1:1", &init)
+            .unwrap()
+            .unwrap();
+        assert_eq!(initiator.handle_handshake("10.0.0.1:2", &accept), Ok(None));
+        assert_eq!(
+            receiver.try_recv().unwrap().peer_id,
+            responder_identity.id()
+        );
+
+        // an Init sent from someone else's address doesn't replace their session,
+        // a new session with them does once it's used
+        let current = responder.get("10.0.0.1:1").unwrap().id;
+        let (_, init, _) = impostor.initiate("10.0.0.1:2", None);
+        assert!(responder
+            .handle_handshake("10.0.0.1:1", &init.unwrap())
+            .is_ok());
+        assert_eq!(responder.get("10.0.0.1:1").unwrap().id, current);
+
+        let (_, init, mut receiver) = initiator.initiate("10.0.0.1:2", None);
+        let accept = responder
+            .handle_handshake("10.0.0.1:1", &init.unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(initiator.handle_handshake("10.0.0.1:2", &accept), Ok(None));
+        let session = receiver.try_recv().unwrap();
+        assert_eq!(responder.get("10.0.0.1:1").unwrap().id, current);
+        assert!(responder.open("10.0.0.1:1", &session.seal(b"new")).is_ok());
+        assert_eq!(responder.get("10.0.0.1:1").unwrap().id, session.id);
+
+        // Inits from the same IP are rate limited, whatever the port
+        let flooded = Sessions::new(Arc::new(Identity::generate()), "10.0.0.1:6".to_owned(), 8);
+        for port in 0..8 {
+            let (id, init, _) = initiator.initiate("10.0.0.1:6", None);
+            initiator.abandon(id);
+            let src = format!("10.0.0.7:{}", port);
+            assert!(flooded.handle_handshake(&src, &init.unwrap()).is_ok());
+        }
+        let (_, init, _) = initiator.initiate("10.0.0.1:6", None);
+        assert_eq!(
+            flooded.handle_handshake("10.0.0.7:100", &init.unwrap()),
+            Err(SessionError::RateLimited)
+        );
+
+        // sessions evicted to make room are gone for sending too
+        let crowded = Sessions::new(
+            Arc::new(Identity::generate()),
+            "10.0.0.1:8".to_owned(),
+            usize::MAX,
+        );
+        for i in 0..=session::MAX_SESSIONS {
+            let (id, init, _) = initiator.initiate("10.0.0.1:8", None);
+            initiator.abandon(id);
+            let src = format!("10.0.{}.{}:1", 100 + i / 256, i % 256);
+            assert!(crowded.handle_handshake(&src, &init.unwrap()).is_ok());
+        }
+        assert!(crowded.get("10.0.100.0:1").is_none());
+        assert!(crowded.get("10.0.100.1:1").is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encrypted_sessions() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.17".to_owned(), 1700);
+        let identity0 = Identity::generate();
+
+        let interface0 = memory_node(&network, &node0, Codec::Json)
+            .identity(identity0.clone())
+            .build()
+            .await
+            .unwrap();
+        let interface1 = memory_node(
+            &network,
+            &Node::new("10.0.0.17".to_owned(), 1701),
+            Codec::Json,
+        )
+        .bootstrap(node0.clone())
+        .timeout(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap();
+        interface1.put("secret", "value").await.unwrap();
+        assert_eq!(interface0.get("secret").await.unwrap(), b"value");
+        assert!(interface0.rpc.stats.handshakes.load(Ordering::Relaxed) >= 1);
+        assert!(interface1.rpc.stats.handshakes.load(Ordering::Relaxed) >= 1);
+
+        // a node sending plaintext still talks with the others
+        let interface2 = memory_node(
+            &network,
+            &Node::new("10.0.0.17".to_owned(), 1702),
+            Codec::Json,
+        )
+        .bootstrap(node0.clone())
+        .plaintext(true)
+        .build()
+        .await
+        .unwrap();
+        assert_eq!(interface2.get("secret").await.unwrap(), b"value");
+
+        // node0 restarts and forgets its sessions, node1 sets up a new one after a timeout
+        interface0.shutdown(false).await;
+        let _interface0 = memory_node(&network, &node0, Codec::Json)
+            .identity(identity0)
+            .build()
+            .await
+            .unwrap();
+        assert!(interface1.ping(node0.clone()).await.is_err());
+        assert!(interface1.ping(node0).await.is_ok());
+        assert_eq!(
+            interface1.rpc.stats.session_errors.load(Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn crypto_puzzles() {
+        assert_eq!(puzzle::leading_zeros(&[0, 0, 0x1F, 0xFF]), 19);
+        let strong = Identity::generate_with(8);
+        assert!(puzzle::solves_static(&strong.id(), 8));
+        let x = puzzle::solve_dynamic(&strong.id(), 8);
+        assert!(puzzle::verify(&strong.id(), &x, 8, 8));
+        let weak = loop {
+            let identity = Identity::generate();
+            if !puzzle::solves_static(&identity.id(), 8) {
+                break identity;
+            }
+        };
+
+        let mut routes = RoutingTable::with_difficulty(
+            Node::with_id("10.0.0.19".to_owned(), 1, strong.id()),
+            2,
+            8,
+        );
+        routes.try_update(Node::with_id("10.0.0.19".to_owned(), 2, weak.id()));
+        assert!(!routes.contains(&weak.id()));
+
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.19".to_owned(), 1900);
+        let interface0 = memory_node(&network, &node0, Codec::Binary)
+            .static_difficulty(8)
+            .dynamic_difficulty(8)
+            .build()
+            .await
+            .unwrap();
+        assert!(puzzle::solves_static(&interface0.node.id, 8));
+        let interface1 = memory_node(
+            &network,
+            &Node::new("10.0.0.19".to_owned(), 1901),
+            Codec::Binary,
+        )
+        .static_difficulty(8)
+        .dynamic_difficulty(8)
+        .bootstrap(node0.clone())
+        .build()
+        .await
+        .unwrap();
+        interface1.put("puzzle", "solved").await.unwrap();
+        assert_eq!(interface0.get("puzzle").await.unwrap(), b"solved");
+
+        // an identity too cheap for the network is refused by the builder...
+        let res = memory_node(
+            &network,
+            &Node::new("10.0.0.19".to_owned(), 1902),
+            Codec::Binary,
+        )
+        .identity(weak.clone())
+        .static_difficulty(8)
+        .build()
+        .await;
+        assert!(matches!(res, Err(KademliaError::InvalidConfig(_))));
+
+        // ...and by the other nodes, whatever its own config says
+        let res = memory_node(
+            &network,
+            &Node::new("10.0.0.19".to_owned(), 1903),
+            Codec::Binary,
+        )
+        .identity(weak)
+        .bootstrap(node0)
+        .bootstrap_retries(0)
+        .timeout(Duration::from_millis(200))
+        .build()
+        .await;
+        assert!(matches!(res, Err(KademliaError::BootstrapFailed(_))));
+        assert!(interface0.rpc.stats.bad_puzzles.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spoofed_responses() {
+        let network = MemoryNetwork::new();
+        let node = Node::new("10.0.0.18".to_owned(), 1800);
+        let interface = memory_node(&network, &node, Codec::Binary)
+            .plaintext(true)
+            .build()
+            .await
+            .unwrap();
+        let target = network.bind("10.0.0.18:1801").unwrap();
+        let attacker = network.bind("10.0.0.18:1802").unwrap();
+
+        let rpc = interface.rpc.clone();
+        let request = tokio::spawn(async move {
+            rpc.make_request(Request::Ping, Node::new("10.0.0.18".to_owned(), 1801))
+                .await
+        });
+        let mut buf = vec![0u8; super::BUF_SIZE];
+        let (len, _) = target.recv_from(&mut buf).await.unwrap();
+        let token = Codec::decode(&buf[..len]).unwrap().token;
+
+        let response = |identity: &Identity| {
+            let mut msg = RpcMessage {
+                token: token.clone(),
+                src: String::new(),
+                src_id: identity.id(),
+                dst: node.get_addr(),
+                msg: Message::Response(Response::Ping),
+                puzzle: Key::random(),
+                public_key: identity.public_key(),
+                signature: Vec::new(),
+            };
+            msg.signature = identity.sign(&msg.signed_bytes());
+            Codec::Binary.encode(&msg).unwrap()
+        };
+
+        // even with the right token, a response from elsewhere doesn't answer the request
+        attacker
+            .send_to(&response(&Identity::generate()), &node.get_addr())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(interface.rpc.stats.unsolicited.load(Ordering::Relaxed), 1);
+        assert_eq!(interface.rpc.pending.lock().unwrap().len(), 1);
+
+        let responder = Identity::generate();
+        target
+            .send_to(&response(&responder), &node.get_addr())
+            .await
+            .unwrap();
+        let res = request.await.unwrap().unwrap().unwrap();
+        assert_eq!(res.src_id, responder.id());
+        assert!(interface.rpc.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forged_entries() {
+        let network = MemoryNetwork::new();
+        let node = Node::new("10.0.0.25".to_owned(), 2500);
+        let interface = memory_node(&network, &node, Codec::Binary)
+            .plaintext(true)
+            .timeout(Duration::from_millis(200))
+            .build()
+            .await
+            .unwrap();
+        let mut honest = vec![];
+        for port in 2501..2506 {
+            honest.push(
+                memory_node(
+                    &network,
+                    &Node::new("10.0.0.25".to_owned(), port),
+                    Codec::Binary,
+                )
+                .bootstrap(node.clone())
+                .build()
+                .await
+                .unwrap(),
+            );
+        }
+        let honest_ids: Vec<Key> = honest.iter().map(|p| p.node.id.clone()).collect();
+
+        // the attacker answers every FindNode with made up ids, and honest ones, at its address
+        let identity = Identity::generate();
+        let attacker = Arc::new(network.bind("10.0.0.25:2599").unwrap());
+        let signer = identity.clone();
+        let message = move |token: Key, dst: String, msg: Message| {
+            let mut msg = RpcMessage {
+                token,
+                src: String::new(),
+                src_id: signer.id(),
+                dst,
+                msg,
+                puzzle: Key::random(),
+                public_key: signer.public_key(),
+                signature: Vec::new(),
+            };
+            msg.signature = signer.sign(&msg.signed_bytes());
+            Codec::Binary.encode(&msg).unwrap()
+        };
+        let forged: Vec<Key> = (0..5).map(|_| Key::random()).collect();
+        {
+            let (attacker, message) = (attacker.clone(), message.clone());
+            let ids: Vec<Key> = forged.iter().chain(&honest_ids).cloned().collect();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; fragment::MAX_DATAGRAM];
+                while let Ok((len, src)) = attacker.recv_from(&mut buf).await {
+                    let req = match Codec::decode(&buf[..len]) {
+                        Ok(req) => req,
+                        Err(_) => continue,
+                    };
+                    let res = match req.msg {
+                        Message::Request(Request::FindNode(target)) => Response::FindNode(
+                            ids.iter()
+                                .map(|id| {
+                                    let node =
+                                        Node::with_id("10.0.0.25".to_owned(), 2599, id.clone());
+                                    let dist = Distance::new(id, &target);
+                                    NodeAndDistance(node, dist)
+                                })
+                                .collect(),
+                        ),
+                        Message::Request(_) => Response::Ping,
+                        Message::Response(_) => continue,
+                    };
+                    let _ = attacker
+                        .send_to(
+                            &message(req.token, src.clone(), Message::Response(res)),
+                            &src,
+                        )
+                        .await;
+                }
+            });
+        }
+        attacker
+            .send_to(
+                &message(
+                    Key::random(),
+                    node.get_addr(),
+                    Message::Request(Request::Ping),
+                ),
+                &node.get_addr(),
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(interface.routes.lock().unwrap().contains(&identity.id()));
+
+        // the entries that don't answer under their id aren't found, nor do they get in
+        let (nodes, _) = interface.lookup_nodes(&Key::random()).await;
+        assert!(nodes.iter().all(|entry| !forged.contains(&entry.0.id)));
+        assert!(nodes
+            .iter()
+            .filter(|entry| honest_ids.contains(&entry.0.id))
+            .all(|entry| entry.0.port != 2599));
+        let routes = interface.routes.lock().unwrap();
+        assert!(forged.iter().all(|id| !routes.contains(id)));
+        assert!(honest_ids.iter().all(|id| routes.contains(id)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn moved_contacts() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.23".to_owned(), 2300);
+        let interface0 = memory_node(&network, &node0, Codec::Binary)
+            .plaintext(true)
+            .timeout(Duration::from_millis(200))
+            .build()
+            .await
+            .unwrap();
+
+        let identity = Identity::generate();
+        let id = identity.id();
+        let address = |interface: &Protocol| interface.routes.lock().unwrap().get(&id).cloned();
+        let interface1 = memory_node(
+            &network,
+            &Node::new("10.0.0.23".to_owned(), 2301),
+            Codec::Binary,
+        )
+        .plaintext(true)
+        .identity(identity.clone())
+        .bootstrap(node0.clone())
+        .build()
+        .await
+        .unwrap();
+        assert_eq!(address(&interface0).unwrap().port, 2301);
+
+        // a request signed by the contact, replayed from elsewhere, doesn't move it
+        let mut msg = RpcMessage {
+            token: Key::random(),
+            src: String::new(),
+            src_id: id.clone(),
+            dst: node0.get_addr(),
+            msg: Message::Request(Request::Ping),
+            puzzle: Key::random(),
+            public_key: identity.public_key(),
+            signature: Vec::new(),
+        };
+        msg.signature = identity.sign(&msg.signed_bytes());
+        let attacker = network.bind("10.0.0.23:2302").unwrap();
+        attacker
+            .send_to(&Codec::Binary.encode(&msg).unwrap(), &node0.get_addr())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(address(&interface0).unwrap().port, 2301);
+        assert!(interface0.moving.lock().unwrap().is_empty());
+
+        // the contact itself, answering at its new address, is moved there
+        interface1.shutdown(false).await;
+        let interface1 = memory_node(
+            &network,
+            &Node::new("10.0.0.23".to_owned(), 2303),
+            Codec::Binary,
+        )
+        .plaintext(true)
+        .identity(identity)
+        .bootstrap(node0)
+        .build()
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(address(&interface0).unwrap().port, 2303);
+        interface1.shutdown(false).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn malformed_datagrams() {
+        let network = MemoryNetwork::new();
+        let node = Node::new("10.0.0.3".to_owned(), 4000);
+        let interface = memory_node(&network, &node, Codec::Binary)
+            .build()
+            .await
+            .unwrap();
+
+        let attacker = network.bind("10.0.0.4:4000").unwrap();
+        let garbage: [&[u8]; 4] = [b"", b"{not json", &[0xDA, 0x01, 0xFF, 0xFF], &[0xF5, 0x00]];
+        for datagram in garbage.iter() {
+            attacker.send_to(datagram, &node.get_addr()).await.unwrap();
+        }
+
+        // the listener is still alive
+        let peer = Node::new("10.0.0.3".to_owned(), 4001);
+        let peer_interface = memory_node(&network, &peer, Codec::Binary)
+            .build()
+            .await
+            .unwrap();
+        assert!(peer_interface.ping(node.clone()).await.is_ok());
+
+        // well formed messages that don't prove the sender's id are dropped before being handled
+        let key = Key::from("forged");
+        let forged = |identity: &Identity, src_id: Key| {
+            let mut msg = RpcMessage {
+                token: Key::random(),
+                src: "10.0.0.4:4000".to_owned(),
+                src_id,
+                dst: node.get_addr(),
+                msg: Message::Request(Request::Store(
+                    key.clone(),
+                    b"forged".to_vec(),
+                    Duration::from_secs(60),
+                )),
+                puzzle: Key::random(),
+                public_key: identity.public_key(),
+                signature: Vec::new(),
+            };
+            msg.signature = identity.sign(&msg.signed_bytes());
+            msg
+        };
+        let attacker_identity = Identity::generate();
+        let mut unsigned = forged(&attacker_identity, attacker_identity.id());
+        unsigned.signature.clear();
+        let mut tampered = forged(&attacker_identity, attacker_identity.id());
+        tampered.token = Key::random();
+        for msg in [
+            unsigned,
+            tampered,
+            // someone else's id
+            forged(&attacker_identity, peer_interface.node.id.clone()),
+        ]
+        .iter()
+        {
+            let datagram = Codec::Binary.encode(msg).unwrap();
+            attacker.send_to(&datagram, &node.get_addr()).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(interface.store.get(&key).is_none());
+        assert!(!interface
+            .routes
+            .lock()
+            .unwrap()
+            .contains(&attacker_identity.id()));
+
+        let stats = &interface.rpc.stats;
+        assert_eq!(stats.bad_signatures.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.decode_errors.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.fragment_errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_values() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.2".to_owned(), 3000);
+        let node1 = Node::new("10.0.0.2".to_owned(), 3001);
+
+        let interface0 = memory_node(&network, &node0, Codec::Json)
+            .build()
+            .await
+            .unwrap();
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .bootstrap(node0)
+            .build()
+            .await
+            .unwrap();
+
+        // not valid UTF-8, stored under a raw key
+        let key = Key([0xAB; super::KEY_LEN]);
+        let value: Vec<u8> = (0..50 * 1024).map(|i| (i % 256) as u8).collect();
+        assert!(interface0.put(key.clone(), &value[..]).await.unwrap() > 0);
+        assert_eq!(value, interface1.get(key).await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn api_errors() {
+        let network = MemoryNetwork::new();
+        let node = Node::new("10.0.0.5".to_owned(), 5000);
+        let interface = memory_node(&network, &node, Codec::Binary)
+            .build()
+            .await
+            .unwrap();
+
+        match interface.get("missing_key".to_owned()).await {
+            Err(KademliaError::NotFound(stats)) => {
+                assert_eq!(stats.responded, 1);
+                assert!(stats.converged);
+            }
+            res => panic!("expected NotFound, got {:?}", res),
+        }
+        assert_eq!(
+            interface
+                .put("some_key".to_owned(), "v".to_owned())
+                .await
+                .unwrap(),
+            1
+        );
+
+        let _first = Protocol::new("127.0.0.1".to_owned(), 1500, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Protocol::new("127.0.0.1".to_owned(), 1500, None).await,
+            Err(KademliaError::Bind(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn custom_config() {
+        let network = MemoryNetwork::new();
+        let node0 = Node::new("10.0.0.6".to_owned(), 6000);
+        let node1 = Node::new("10.0.0.6".to_owned(), 6001);
+
+        let interface0 = memory_node(&network, &node0, Codec::Binary)
+            .k_param(4)
+            .alpha(1)
+            .timeout(Duration::from_millis(500))
+            .build()
+            .await
+            .unwrap();
+        // small datagrams, every Store gets fragmented
+        let interface1 = memory_node(&network, &node1, Codec::Binary)
+            .buf_size(512)
+            .bootstrap(node0.clone())
+            .build()
+            .await
+            .unwrap();
+
+        assert!(interface0
+            .routes
+            .lock()
+            .unwrap()
+            .kbuckets
+            .iter()
+            .all(|kb| kb.size == 4));
+        assert_eq!(interface1.config.k_param, super::K_PARAM);
+
+        let value = "y".repeat(4096);
+        assert_eq!(
+            interface1
+                .put("some_key".to_owned(), value.clone())
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            value.as_bytes(),
+            &interface0.get("some_key").await.unwrap()[..]
+        );
+
+        // the small node still receives replies larger than its own datagrams
+        let mut others = vec![];
+        for port in 6010..6040 {
+            let node = Node::new("10.0.0.6".to_owned(), port);
+            let builder = memory_node(&network, &node, Codec::Binary).bootstrap(node0.clone());
+            others.push(builder.build().await.unwrap());
+        }
+        others[29].put("big_network", "value").await.unwrap();
+        let (_, stats) = interface1.lookup_nodes(&Key::random()).await;
+        assert!(stats.responded > 2);
+        assert_eq!(interface1.get("big_network").await.unwrap(), b"value");
+        assert_eq!(
+            interface1.rpc.stats.session_errors.load(Ordering::Relaxed),
+            0
+        );
+
+        assert!(matches!(
+            memory_node(
+                &network,
+                &Node::new("10.0.0.6".to_owned(), 6002),
+                Codec::Binary
+            )
+            .alpha(0)
+            .build()
+            .await,
+            Err(KademliaError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn main_test() {
         let node0 = Node::new(utils::get_local_ip().unwrap(), 1337);
         let node1 = Node::new(utils::get_local_ip().unwrap(), 1338);
         let node2 = Node::new(utils::get_local_ip().unwrap(), 1339);
 
-        let interface0 = Protocol::new(node0.ip.clone(), node0.port.clone(), None);
-        let interface1 = Protocol::new(node1.ip.clone(), node1.port.clone(), Some(node0.clone()));
-        let interface2 = Protocol::new(node2.ip.clone(), node2.port.clone(), Some(node0.clone()));
+        // every node is on the local host
+        let local = |node: &Node| {
+            ProtocolBuilder::new()
+                .bind(node.ip.clone(), node.port)
+                .ip_limits(IpLimits::disabled())
+                .max_handshakes(usize::MAX)
+        };
+        let interface0 = local(&node0).build().await.unwrap();
+        let interface1 = local(&node1)
+            .bootstrap(node0.clone())
+            .build()
+            .await
+            .unwrap();
+        let interface2 = local(&node2)
+            .bootstrap(node0.clone())
+            .build()
+            .await
+            .unwrap();
+
+        interface0
+            .put("some_key".to_owned(), "some_value".to_owned())
+            .await
+            .unwrap();
+        let get_res_1 = interface1.get("some_key".to_owned()).await.unwrap();
+        let get_res_2 = interface2.get("some_key".to_owned()).await.unwrap();
+
+        assert_eq!(b"some_value", &get_res_1[..]);
+        assert_eq!(get_res_1, get_res_2);
+    }
+
+    #[test]
+    fn disjoint_lookup() {
+        let target = Key::new("disjoint".to_owned());
+        let mut nodes: Vec<NodeAndDistance> = (2000..2030)
+            .map(|port| {
+                let node = Node::new("10.0.0.20".to_owned(), port);
+                let dist = Distance::new(&node.id, &target);
+                NodeAndDistance(node, dist)
+            })
+            .collect();
+        nodes.sort_by_key(|a| a.1);
+        // the closest seed lies, pointing to far away nodes
+        let liar = nodes[10].clone();
+
+        let mut lookup = DisjointLookup::new(&target, nodes[10..14].to_vec(), 2, 3, 1);
+        assert_eq!(lookup.paths.len(), 2);
+        while !lookup.is_done() {
+            for (path, query) in lookup.next_round() {
+                if query == liar {
+                    lookup.add_response(path, query, nodes[20..25].to_vec());
+                } else {
+                    lookup.add_response(path, query, nodes[..5].to_vec());
+                }
+            }
+        }
+        let stats = lookup.stats();
+        assert_eq!((stats.paths, stats.agreeing), (2, 2));
+        // no node answered on both paths, the liar only steered its own
+        let paths = lookup.path_results();
+        assert!(paths[0].iter().all(|entry| !paths[1].contains(entry)));
+        assert_eq!(paths[1], nodes[..3].to_vec());
+
+        // most paths get the right value
+        let mut lookup = DisjointLookup::new(&target, nodes[10..16].to_vec(), 3, 3, 1);
+        while !lookup.is_done() {
+            for (path, query) in lookup.next_round() {
+                let value = if query == liar { b"forged" } else { b"honest" };
+                lookup.add_value(path, query, value.to_vec(), Duration::from_secs(1));
+            }
+        }
+        let (value, _, votes) = lookup.value().unwrap();
+        assert_eq!((&value[..], votes), (&b"honest"[..], 2));
+        assert_eq!(lookup.stats().agreeing, 2);
+
+        // the copy goes to the closest node that answered without the value
+        let mut lookup = DisjointLookup::new(&target, nodes[10..13].to_vec(), 1, 3, 3);
+        while !lookup.is_done() {
+            for (path, query) in lookup.next_round() {
+                if query == nodes[0] || query == nodes[2] {
+                    lookup.add_value(path, query, b"v".to_vec(), Duration::from_secs(1));
+                } else {
+                    lookup.add_response(path, query, nodes[..3].to_vec());
+                }
+            }
+        }
+        assert_eq!(lookup.cache_target(), Some(&nodes[1]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disjoint_paths() {
+        let network = MemoryNetwork::new();
+        let root = Node::new("10.0.0.20".to_owned(), 2100);
+        let mut interfaces = vec![];
+
+        for port in 2100..2120 {
+            let node = Node::new("10.0.0.20".to_owned(), port);
+            let mut builder = memory_node(&network, &node, Codec::Binary).disjoint_paths(3);
+            if port != root.port {
+                builder = builder.bootstrap(root.clone());
+            }
+            interfaces.push(builder.build().await.unwrap());
+        }
+
+        // every path stores on its own closest nodes
+        let acked = interfaces[0].put("disjoint", "paths").await.unwrap();
+        assert!(acked > interfaces[0].config.k_param.min(interfaces.len()) / 3);
+        assert_eq!(interfaces[19].get("disjoint").await.unwrap(), b"paths");
+
+        let (nodes, stats) = interfaces[5].lookup_nodes(&Key::random()).await;
+        assert!(!nodes.is_empty());
+        assert_eq!(stats.paths, 3);
+        assert!(stats.converged);
+        assert_eq!(stats.agreeing, 3);
+
+        let res = memory_node(
+            &network,
+            &Node::new("10.0.0.20".to_owned(), 2120),
+            Codec::Binary,
+        )
+        .disjoint_paths(0)
+        .build()
+        .await;
+        assert!(matches!(res, Err(KademliaError::InvalidConfig(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ip_limits() {
+        assert_eq!(routing::subnet("192.168.1.7"), "192.168.1.0/24");
+        assert_eq!(routing::subnet("2001:db8:1:2:3::1"), "2001:db8:1:2::/64");
+
+        let limits = IpLimits {
+            bucket_ip: 1,
+            bucket_subnet: 2,
+            table_ip: 3,
+            table_subnet: 4,
+        };
+        let own = Node::new("10.0.0.21".to_owned(), 2100);
+        let mut routes = RoutingTable::with_limits(own.clone(), 20, 0, limits);
+        let count = |routes: &RoutingTable, prefix: &str| {
+            routes
+                .kbuckets
+                .iter()
+                .map(|kb| {
+                    kb.nodes
+                        .iter()
+                        .filter(|x| x.id != own.id && x.ip.starts_with(prefix))
+                        .count()
+                })
+                .collect::<Vec<usize>>()
+        };
+
+        // a single host on many ports, we don't count
+        for port in 2101..2200 {
+            routes.try_update(Node::new("10.0.0.21".to_owned(), port));
+        }
+        let per_bucket = count(&routes, "10.0.0.21");
+        assert!(per_bucket.iter().all(|n| *n <= 1));
+        assert_eq!(per_bucket.iter().sum::<usize>(), 3);
+        // the subnet only has room for one more
+        for port in 2100..2200 {
+            routes.try_update(Node::new("10.0.0.22".to_owned(), port));
+        }
+        assert_eq!(count(&routes, "10.0.0.22").iter().sum::<usize>(), 1);
+        // other subnets are still welcome
+        for i in 1..100 {
+            routes.try_update(Node::new(format!("10.0.1.{}", i), 2100));
+        }
+        assert_eq!(count(&routes, "10.0.1.").iter().sum::<usize>(), 4);
+
+        // known contacts are still refreshed
+        let contact = routes
+            .kbuckets
+            .iter()
+            .flat_map(|kb| &kb.nodes)
+            .find(|x| x.id != own.id)
+            .unwrap()
+            .clone();
+        routes.try_update(contact.clone());
+        assert!(routes.contains(&contact.id));
+        // and moved within the limits, where their old address doesn't count,
+        // but not to an IP that is already full
+        let contact = routes
+            .kbuckets
+            .iter()
+            .flat_map(|kb| &kb.nodes)
+            .find(|x| x.ip.starts_with("10.0.1."))
+            .unwrap()
+            .clone();
+        let mut moved = contact.clone();
+        moved.ip = "10.0.1.200".to_owned();
+        routes.try_update(moved.clone());
+        assert_eq!(routes.get(&contact.id).unwrap().ip, moved.ip);
+        moved.ip = "10.0.0.21".to_owned();
+        routes.try_update(moved);
+        assert_eq!(routes.get(&contact.id).unwrap().ip, "10.0.1.200");
+
+        let network = MemoryNetwork::new();
+        let res = memory_node(
+            &network,
+            &Node::new("10.0.0.21".to_owned(), 2100),
+            Codec::Binary,
+        )
+        .ip_limits(IpLimits {
+            table_ip: 0,
+            ..IpLimits::default()
+        })
+        .build()
+        .await;
+        assert!(matches!(res, Err(KademliaError::InvalidConfig(_))));
+        let res = memory_node(
+            &network,
+            &Node::new("10.0.0.21".to_owned(), 2100),
+            Codec::Binary,
+        )
+        .max_handshakes(0)
+        .build()
+        .await;
+        assert!(matches!(res, Err(KademliaError::InvalidConfig(_))));
 
-        interface0.put("some_key".to_owned(), "some_value".to_owned());
-        let get_res_1 = interface1.get("some_key".to_owned());
-        let get_res_2 = interface2.get("some_key".to_owned());
+        // IPv6 senders, seen as "[ip]:port", are capped by their /64 too
+        assert_eq!(
+            Node::new("2001:db8::1".to_owned(), 2400).get_addr(),
+            "[2001:db8::1]:2400"
+        );
+        assert_eq!(
+            utils::parse_addr("[2001:db8::1]:2400"),
+            Some(("2001:db8::1".to_owned(), 2400))
+        );
+        assert_eq!(utils::parse_addr("2001:db8::1:2400"), None);
+        let root = Node::new("10.0.0.24".to_owned(), 2400);
+        let interface = memory_node(&network, &root, Codec::Binary)
+            .ip_limits(IpLimits {
+                table_subnet: 2,
+                ..IpLimits::disabled()
+            })
+            .build()
+            .await
+            .unwrap();
+        let mut peers = vec![];
+        for i in 1..=3 {
+            let node = Node::new(format!("2001:db8::{}", i), 2400);
+            peers.push(
+                memory_node(&network, &node, Codec::Binary)
+                    .bootstrap(root.clone())
+                    .build()
+                    .await
+                    .unwrap(),
+            );
+        }
+        let contacts = interface.routes.lock().unwrap().contacts();
+        assert_eq!(contacts.len(), 2);
+        assert!(contacts.iter().all(|c| c.node.ip.starts_with("2001:db8::")));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn memory_network() {
+        let network = MemoryNetwork::new();
+        let mut interfaces = vec![];
+
+        for i in 0..200 {
+            let node = Node::new("10.0.0.1".to_owned(), 2000 + i);
+            let mut builder = memory_node(&network, &node, Codec::Binary);
+            if let Some(bootstrap) = interfaces.first().map(|p: &Protocol| p.node.clone()) {
+                builder = builder.bootstrap(bootstrap);
+            }
 
-        assert_eq!("some_value", get_res_1.clone().unwrap());
-        assert_eq!(get_res_1.unwrap(), get_res_2.unwrap());
+            // mixing codecs, every node must understand both
+            let codec = if i % 2 == 0 {
+                Codec::Binary
+            } else {
+                Codec::Json
+            };
+
+            interfaces.push(builder.codec(codec).build().await.unwrap());
+        }
+
+        interfaces[0]
+            .put("some_key".to_owned(), "some_value".to_owned())
+            .await
+            .unwrap();
+        // lots of concurrent lookups, all of them on the same runtime
+        let mut joins = vec![];
+        for interface in interfaces.iter().cycle().take(1000) {
+            let interface = interface.clone();
+            joins.push(tokio::spawn(async move {
+                interface.get("some_key".to_owned()).await
+            }));
+        }
+        for j in joins {
+            assert_eq!(b"some_value", &j.await.unwrap().unwrap()[..]);
+        }
     }
 
     #[test]
-    fn dump_interface() {
-        let interface = Protocol::new(utils::get_local_ip().unwrap(), 1400, None);
+    fn simulation() {
+        let config = SimConfig {
+            nodes: 300,
+            lookups: 50,
+            puts: 20,
+            seed: 42,
+            ..Default::default()
+        };
+
+        let stats = Simulator::new(config.clone()).unwrap().run();
+        assert_eq!(stats.joins, 300);
+        assert!(stats.lookup_success_rate() > 0.5);
+        assert_eq!(stats.get_success_rate(), 1.0);
+
+        // same seed, same experiment
+        assert_eq!(stats, Simulator::new(config.clone()).unwrap().run());
+
+        let disjoint = Simulator::new(SimConfig {
+            disjoint_paths: 3,
+            ..config.clone()
+        })
+        .unwrap()
+        .run();
+        assert_eq!(disjoint.get_success_rate(), 1.0);
+
+        let res = Simulator::new(SimConfig {
+            min_latency: 100,
+            max_latency: 10,
+            ..config.clone()
+        });
+        assert!(matches!(res, Err(KademliaError::InvalidConfig(_))));
+        let res = Simulator::new(SimConfig {
+            loss_rate: 1.5,
+            ..config
+        });
+        assert!(matches!(res, Err(KademliaError::InvalidConfig(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dump_interface() {
+        let interface = Protocol::new(utils::get_local_ip().unwrap(), 1400, None)
+            .await
+            .unwrap();
         utils::dump_interface_state(&interface, "dumps/interface.json");
     }
 }
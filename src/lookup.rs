@@ -0,0 +1,370 @@
+use super::key::Key;
+use super::node::Node;
+use super::routing::NodeAndDistance;
+
+use std::collections::{BinaryHeap, HashSet};
+use std::time::Duration;
+
+// summary of how a lookup went
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LookupStats {
+    pub rounds: usize,
+    pub queried: usize,
+    pub responded: usize,
+    pub timed_out: usize,
+    // the k closest nodes seen during the lookup all answered
+    pub converged: bool,
+    // number of disjoint paths, see DisjointLookup
+    pub paths: usize,
+    // paths agreeing with the result: they got the same value or came across the closest node
+    pub agreeing: usize,
+}
+
+// State of an iterative lookup towards a target key.
+// It doesn't know anything about the network: the caller asks for the next
+// round of nodes to query and feeds back the answers. This way the same algorithm
+// is shared by Protocol (real RPCs) and the simulator (virtual network)
+#[derive(Debug)]
+pub struct Lookup {
+    pub target: Key,
+    // number of results
+    pub k: usize,
+    // queries per round
+    pub alpha: usize,
+    // number of rounds of (up to alpha) parallel queries performed so far
+    pub rounds: usize,
+    // nodes visited
+    queried: HashSet<NodeAndDistance>,
+    // nodes to visit
+    to_query: BinaryHeap<NodeAndDistance>,
+    // nodes that answered our queries
+    found: Vec<NodeAndDistance>,
+    // nodes that didn't
+    failed: HashSet<NodeAndDistance>,
+}
+
+impl Lookup {
+    pub fn new(target: &Key, seeds: Vec<NodeAndDistance>, k: usize, alpha: usize) -> Self {
+        let to_query = BinaryHeap::from(seeds);
+        let mut queried = HashSet::new();
+
+        for entry in &to_query {
+            queried.insert(entry.clone());
+        }
+
+        Self {
+            target: target.clone(),
+            k,
+            alpha,
+            rounds: 0,
+            queried,
+            to_query,
+            found: Vec::new(),
+            failed: HashSet::new(),
+        }
+    }
+
+    // done when nothing is left to query, or when the k closest nodes that answered
+    // are all closer than the best node left to query
+    pub fn is_done(&self) -> bool {
+        let next = match self.to_query.peek() {
+            Some(next) => next,
+            None => return true,
+        };
+        if self.found.len() < self.k {
+            return false;
+        }
+
+        let mut distances: Vec<_> = self.found.iter().map(|entry| entry.1).collect();
+        distances.sort();
+        distances[self.k - 1] < next.1
+    }
+
+    // pops the (up to alpha) closest nodes that still have to be queried
+    pub fn next_round(&mut self) -> Vec<NodeAndDistance> {
+        let mut queries = Vec::with_capacity(self.alpha);
+
+        while queries.len() < self.alpha {
+            match self.to_query.pop() {
+                Some(entry) => queries.push(entry),
+                None => break,
+            }
+        }
+
+        if !queries.is_empty() {
+            self.rounds += 1;
+        }
+
+        queries
+    }
+
+    // records the answer of a queried node, unseen entries are scheduled for querying
+    pub fn add_response(&mut self, query: NodeAndDistance, entries: Vec<NodeAndDistance>) {
+        self.found.push(query);
+
+        for entry in entries {
+            if self.queried.insert(entry.clone()) {
+                self.to_query.push(entry);
+            }
+        }
+    }
+
+    // records a query that got no (usable) answer
+    pub fn add_failure(&mut self, query: NodeAndDistance) {
+        self.failed.insert(query);
+    }
+
+    pub fn stats(&self) -> LookupStats {
+        let mut seen: Vec<&NodeAndDistance> = self.queried.iter().collect();
+        seen.sort_by_key(|a| a.1);
+
+        let converged = !self.found.is_empty()
+            && self.is_done()
+            && !seen
+                .iter()
+                .take(self.k)
+                .any(|entry| self.failed.contains(*entry));
+
+        LookupStats {
+            rounds: self.rounds,
+            queried: self.found.len() + self.failed.len(),
+            responded: self.found.len(),
+            timed_out: self.failed.len(),
+            converged,
+            paths: 1,
+            agreeing: if self.found.is_empty() { 0 } else { 1 },
+        }
+    }
+
+    // the k closest nodes that answered
+    pub fn result(mut self) -> Vec<NodeAndDistance> {
+        self.found.sort_by_key(|a| a.1);
+        self.found.truncate(self.k);
+
+        self.found
+    }
+}
+
+/*
+    S/Kademlia lookup along d disjoint paths: every path is a Lookup of its own, seeded
+    with a share of the closest nodes, and a node is queried by one path at most. A malicious
+    node can only steer the paths going through it, so as long as one path avoids the
+    attackers the closest nodes are still found. With d = 1 it's a plain Lookup
+*/
+#[derive(Debug)]
+pub struct DisjointLookup {
+    pub paths: Vec<Lookup>,
+    // entries (id and address) already assigned to a path
+    assigned: HashSet<Node>,
+    // ids that answered on some path, under that id (see Protocol::query)
+    claimed: HashSet<Key>,
+    // nodes every path came across, assigned to it or not
+    heard: Vec<HashSet<Key>>,
+    // value (and its ttl) found by every path, a path stops once it gets one
+    values: Vec<Option<(Vec<u8>, Duration)>>,
+    // nodes that answered every path without the value
+    without_value: Vec<Vec<NodeAndDistance>>,
+}
+
+impl DisjointLookup {
+    pub fn new(
+        target: &Key,
+        mut seeds: Vec<NodeAndDistance>,
+        d: usize,
+        k: usize,
+        alpha: usize,
+    ) -> Self {
+        seeds.sort_by_key(|a| a.1);
+        // at least a path, even without seeds
+        let d = d.min(seeds.len()).max(1);
+
+        // closest seeds are dealt round-robin
+        let mut shares = vec![Vec::new(); d];
+        for (i, seed) in seeds.into_iter().enumerate() {
+            shares[i % d].push(seed);
+        }
+
+        let heard: Vec<HashSet<Key>> = shares
+            .iter()
+            .map(|share| share.iter().map(|entry| entry.0.id.clone()).collect())
+            .collect();
+        let assigned = shares
+            .iter()
+            .flatten()
+            .map(|entry| entry.0.clone())
+            .collect();
+        let paths = shares
+            .into_iter()
+            .map(|share| Lookup::new(target, share, k, alpha))
+            .collect();
+
+        Self {
+            paths,
+            assigned,
+            claimed: HashSet::new(),
+            heard,
+            values: vec![None; d],
+            without_value: vec![Vec::new(); d],
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.paths
+            .iter()
+            .zip(&self.values)
+            .all(|(path, value)| path.is_done() || value.is_some())
+    }
+
+    // next round of every path still running, with the index of the path
+    pub fn next_round(&mut self) -> Vec<(usize, NodeAndDistance)> {
+        let mut queries = Vec::new();
+
+        for (i, path) in self.paths.iter_mut().enumerate() {
+            if self.values[i].is_none() {
+                queries.extend(path.next_round().into_iter().map(|query| (i, query)));
+            }
+        }
+
+        queries
+    }
+
+    // entries already assigned to another path are only remembered as heard of
+    pub fn add_response(
+        &mut self,
+        path: usize,
+        query: NodeAndDistance,
+        entries: Vec<NodeAndDistance>,
+    ) {
+        self.claimed.insert(query.0.id.clone());
+        self.without_value[path].push(query.clone());
+        // an entry with the id of a node that answered elsewhere, or handed out already,
+        // isn't queried again: a forged one can't take an honest id away from the other paths
+        let mut unclaimed = Vec::new();
+        for entry in entries {
+            self.heard[path].insert(entry.0.id.clone());
+            if self.values[path].is_none()
+                && !self.claimed.contains(&entry.0.id)
+                && self.assigned.insert(entry.0.clone())
+            {
+                unclaimed.push(entry);
+            }
+        }
+
+        self.paths[path].add_response(query, unclaimed);
+    }
+
+    pub fn add_failure(&mut self, path: usize, query: NodeAndDistance) {
+        self.paths[path].add_failure(query);
+    }
+
+    pub fn add_value(
+        &mut self,
+        path: usize,
+        query: NodeAndDistance,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) {
+        self.claimed.insert(query.0.id.clone());
+        self.paths[path].add_response(query, Vec::new());
+        self.values[path] = Some((value, ttl));
+    }
+
+    // the value found by most paths and how many found it, ties go to the path with the closest seeds
+    pub fn value(&self) -> Option<(Vec<u8>, Duration, usize)> {
+        let mut best: Option<(Vec<u8>, Duration, usize)> = None;
+
+        for (value, ttl) in self.values.iter().flatten() {
+            let votes = self
+                .values
+                .iter()
+                .flatten()
+                .filter(|(other, _)| other == value)
+                .count();
+            if best.as_ref().is_none_or(|(_, _, most)| votes > *most) {
+                best = Some((value.clone(), *ttl, votes));
+            }
+        }
+
+        best
+    }
+
+    // As in the paper, the closest node that answered without the value is where a copy
+    // gets cached, picked among the paths that found the value value() returns
+    pub fn cache_target(&self) -> Option<&NodeAndDistance> {
+        let (value, _, _) = self.value()?;
+
+        self.values
+            .iter()
+            .zip(&self.without_value)
+            .filter(|(found, _)| found.as_ref().map(|(v, _)| v) == Some(&value))
+            .flat_map(|(_, nodes)| nodes)
+            .min_by_key(|a| a.1)
+    }
+
+    pub fn stats(&self) -> LookupStats {
+        let mut stats = LookupStats {
+            paths: self.paths.len(),
+            ..LookupStats::default()
+        };
+
+        for path in &self.paths {
+            let path_stats = path.stats();
+            // the paths run side by side
+            stats.rounds = stats.rounds.max(path_stats.rounds);
+            stats.queried += path_stats.queried;
+            stats.responded += path_stats.responded;
+            stats.timed_out += path_stats.timed_out;
+        }
+
+        let mut seen: Vec<&NodeAndDistance> =
+            self.paths.iter().flat_map(|path| &path.queried).collect();
+        seen.sort_by_key(|a| a.1);
+        stats.converged = stats.responded > 0
+            && self.is_done()
+            && !seen
+                .iter()
+                .take(self.k())
+                .any(|entry| self.paths.iter().any(|path| path.failed.contains(*entry)));
+
+        stats.agreeing = match self.value() {
+            Some((_, _, votes)) => votes,
+            None => match self.closest() {
+                Some(closest) => self
+                    .heard
+                    .iter()
+                    .filter(|heard| heard.contains(&closest.0.id))
+                    .count(),
+                None => 0,
+            },
+        };
+
+        stats
+    }
+
+    // the k closest nodes that answered, whatever their path
+    pub fn result(self) -> Vec<NodeAndDistance> {
+        let k = self.k();
+        let mut found: Vec<NodeAndDistance> =
+            self.paths.into_iter().flat_map(|path| path.found).collect();
+        found.sort_by_key(|a| a.1);
+        found.truncate(k);
+
+        found
+    }
+
+    // the k closest nodes that answered on every path
+    pub fn path_results(self) -> Vec<Vec<NodeAndDistance>> {
+        self.paths.into_iter().map(Lookup::result).collect()
+    }
+
+    fn k(&self) -> usize {
+        self.paths[0].k
+    }
+
+    fn closest(&self) -> Option<&NodeAndDistance> {
+        self.paths
+            .iter()
+            .flat_map(|path| &path.found)
+            .min_by_key(|a| a.1)
+    }
+}
@@ -1,48 +1,89 @@
 extern crate kademlia_dht;
+use kademlia_dht::config::ProtocolBuilder;
 use kademlia_dht::node::Node;
 use kademlia_dht::protocol::Protocol;
+use kademlia_dht::routing::IpLimits;
+use kademlia_dht::simulator::{SimConfig, Simulator};
 use kademlia_dht::utils;
 
 const BIG_TEST: bool = true;
 
-// be careful with the net size, for example my computer can't spawn too many threads
-// messages may also exceed the buffer size used for streaming (see issue #1)
+// runs the discrete-event simulator instead of real nodes, no threads or sockets involved
+const SIMULATION: bool = false;
+const SIM_SIZE: usize = 10_000;
+
+// every node is a handful of tasks on the same runtime, the limit is the number of free ports
 const NET_SIZE: usize = 10;
 
-fn test_big_net() {
+// every node runs on this host, the IP limits would keep them out of each other's routing tables
+async fn local_interface(node: &Node, bootstrap: Option<Node>) -> Protocol {
+	let mut builder = ProtocolBuilder::new().bind(node.ip.clone(), node.port).ip_limits(IpLimits::disabled()).max_handshakes(usize::MAX);
+	if let Some(bootstrap) = bootstrap {
+		builder = builder.bootstrap(bootstrap);
+	}
+
+	builder.build().await.unwrap()
+}
+
+async fn test_big_net() {
 	let mut interfaces: Vec<Protocol> = Vec::with_capacity(NET_SIZE);
-	let mut base_port = 8000;
 
 	let root = Node::new(utils::get_local_ip().unwrap(), 7999);
-	let root_interface = Protocol::new(root.ip.clone(), root.port.clone(), None);
-	root_interface.put("MAIN_KEY".to_owned(), "MAIN_VALUE".to_owned());
+	let root_interface = local_interface(&root, None).await;
+	if let Err(e) = root_interface.put("MAIN_KEY".to_owned(), "MAIN_VALUE".to_owned()).await {
+		eprintln!("[FAILED] PUT of MAIN_KEY: {}", e);
+	}
 
-	for i in 0..(NET_SIZE - 1) {
+	for (i, base_port) in (8000..).enumerate().take(NET_SIZE - 1) {
 		let node = Node::new(utils::get_local_ip().unwrap(), base_port);
 
-		interfaces.push(Protocol::new(node.ip, node.port, Some(root.clone())));
+		interfaces.push(local_interface(&node, Some(root.clone())).await);
 		println!(
 			"[+] Created interface for index: {} on port: {}",
 			i, base_port
 		);
-
-		base_port += 1;
 	}
 
 	for (index, interface) in interfaces.iter().enumerate() {
 		println!("[+] Putting <key, value> pair for index: {}", index);
-		interface.put(format!("key_{}", index), format!("value_{}", index));
+		match interface.put(format!("key_{}", index), format!("value_{}", index)).await {
+			Ok(acked) => println!("[*] Stored on {} nodes", acked),
+			Err(e) => eprintln!("[FAILED] PUT for index {}: {}", index, e),
+		}
 	}
 
 	for (index, interface) in interfaces.iter().enumerate() {
-		let res = interface.get(format!("key_{}", index));
-		println!("[*] Looking for key_{}, got {}", index, res.unwrap());
+		let res = interface.get(format!("key_{}", index)).await;
+		match res {
+			Ok(v) => println!("[*] Looking for key_{}, got {}", index, String::from_utf8_lossy(&v)),
+			Err(e) => println!("[*] Looking for key_{}, failed: {}", index, e),
+		}
 	}
 }
 
-fn main() {
-	if BIG_TEST {
-		test_big_net();
+fn run_simulation() {
+	let config = SimConfig {
+		nodes: SIM_SIZE,
+		seed: 1,
+		..Default::default()
+	};
+	println!("[+] Simulating a network of {} nodes with seed {}", config.nodes, config.seed);
+
+	let stats = Simulator::new(config).unwrap().run();
+	println!("[*] {:#?}", stats);
+	println!("[*] lookup success rate: {:.3}", stats.lookup_success_rate());
+	println!("[*] average lookup hops: {:.3}", stats.avg_lookup_hops());
+	println!("[*] get success rate: {:.3}", stats.get_success_rate());
+	println!("[*] average get hops: {:.3}", stats.avg_get_hops());
+	println!("[*] messages per operation: {:.3}", stats.messages_per_operation());
+}
+
+#[tokio::main]
+async fn main() {
+	if SIMULATION {
+		run_simulation();
+	} else if BIG_TEST {
+		test_big_net().await;
 	} else {
 		let node0 = Node::new(utils::get_local_ip().unwrap(), 1337);
 		println!("[+] Created node0: {:?}", node0);
@@ -53,24 +94,25 @@ fn main() {
 		let node2 = Node::new(utils::get_local_ip().unwrap(), 1339);
 		println!("[+] Created node2: {:?}", node2);
 
-		let interface0 = Protocol::new(node0.ip.clone(), node0.port.clone(), None);
+		let interface0 = local_interface(&node0, None).await;
 		println!("[+] Initialized Kademlia Protocol for node0 (interface0)");
 
-		let interface1 = Protocol::new(node1.ip.clone(), node1.port.clone(), Some(node0.clone()));
+		let interface1 = local_interface(&node1, Some(node0.clone())).await;
 		println!("[+] Initialized Kademlia Protocol for node1 (interface1)");
 
-		let interface2 = Protocol::new(node2.ip.clone(), node2.port.clone(), Some(node0.clone()));
+		let interface2 = local_interface(&node2, Some(node0.clone())).await;
 		println!("[+] Initialized Kademlia Protocol for node2 (interface2)");
 
 		println!("\n--------------------------------------");
 		println!("Calling Kademlia API");
 
-		interface0.put("some_key".to_owned(), "some_value".to_owned());
+		let put_res = interface0.put("some_key".to_owned(), "some_value".to_owned()).await;
 		println!("\t[*] node0 > called PUT for key: 'some_key' and value: 'some_value'");
+		println!("\t\t[+] Replicas: {:?}", put_res);
 
-		let get_res = interface2.get("some_key".to_owned());
+		let get_res = interface2.get("some_key".to_owned()).await;
 		println!("\t[*] node2 > called GET on key: 'some_key'");
-		println!("\t\t[+] Extracted: {:?}", get_res);
+		println!("\t\t[+] Extracted: {:?}", get_res.map(|v| String::from_utf8_lossy(&v).into_owned()));
 		println!("--------------------------------------\n");
 
 		utils::dump_interface_state(&interface0, "dumps/interface0.json");
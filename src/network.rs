@@ -1,25 +1,37 @@
 use serde::{Deserialize, Serialize};
 
+use super::codec::{Codec, CodecError};
+use super::config::ProtocolConfig;
+use super::fragment::{self, FragmentError, Reassembler};
+use super::identity::{self, Identity, PUBLIC_KEY_LEN};
 use super::key::Key;
 use super::node::*;
+use super::puzzle;
 use super::routing::FindValueResult;
 use super::routing::NodeAndDistance;
-use super::BUF_SIZE;
-use super::TIMEOUT;
+use super::session::{self, Session, SessionError, Sessions};
+use super::transport::Transport;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
 
 use std::collections::HashMap;
-use std::net::UdpSocket;
-use std::str;
-use std::sync::mpsc;
+use std::fmt::{Display, Error, Formatter};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     Ping,
-    Store(String, String),
+    // key, value and time to live
+    Store(
+        Key,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        std::time::Duration,
+    ),
     FindNode(Key),
-    FindValue(String),
+    FindValue(Key),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -31,7 +43,6 @@ pub enum Response {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
-    Abort,
     Request(Request),
     Response(Response),
 }
@@ -40,158 +51,508 @@ pub enum Message {
 pub struct RpcMessage {
     pub token: Key,
     pub src: String,
+    // id of the sender, it doesn't depend on its address
+    pub src_id: Key,
     pub dst: String,
     pub msg: Message,
+    // the sender's public key, src_id is its hash
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+    // solution of the dynamic puzzle for src_id, see puzzle.rs
+    pub puzzle: Key,
+    // signature of signed_bytes by the sender, see Rpc::message
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl RpcMessage {
+    // everything but src, which the receiver replaces with the address the datagram came from
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&(
+            &self.token,
+            &self.src_id,
+            &self.dst,
+            &self.msg,
+            &self.puzzle,
+        ))
+        .expect("[FAILED] RpcMessage::signed_bytes --> Failed to serialize message")
+    }
+
+    // the signature is valid and made with the key src_id belongs to
+    pub fn verify(&self) -> bool {
+        identity::verify(
+            &self.src_id,
+            &self.public_key,
+            &self.signed_bytes(),
+            &self.signature,
+        )
+    }
 }
 
 #[derive(Debug)]
 pub struct ReqWrapper {
     pub token: Key,
     pub src: String,
+    pub src_id: Key,
     pub payload: Request,
 }
 
+// a response together with the id of the node that sent it
+#[derive(Debug)]
+pub struct ResWrapper {
+    pub src_id: Key,
+    pub payload: Response,
+}
+
+// a request waiting for its response, only dst is allowed to answer it
+#[derive(Debug)]
+pub struct PendingRequest {
+    // address and id the request was sent to, the id is a placeholder for nodes known by address
+    pub dst: String,
+    pub dst_id: Key,
+    pub sender: oneshot::Sender<ResWrapper>,
+}
+
+#[derive(Debug)]
+pub enum NetworkError {
+    Io(io::Error),
+    Encode(CodecError),
+    Decode(CodecError),
+    Fragment(FragmentError),
+    // encoded message larger than fragment::MAX_MESSAGE_SIZE
+    MessageTooLarge(usize),
+    // message addressed to someone else
+    WrongDestination(String),
+    // unsigned message, or signed with a key src_id doesn't belong to
+    BadSignature(String),
+    // the sender's id doesn't solve the crypto puzzles
+    BadPuzzle(String),
+    // no encrypted session could be set up with the given address
+    Handshake(String),
+    Session(SessionError),
+    // the other end of an internal channel went away
+    ChannelClosed,
+}
+
+// counters about the traffic handled by a Rpc, errors are counted by kind
+#[derive(Debug, Default)]
+pub struct NetworkStats {
+    pub received: AtomicU64,
+    pub sent: AtomicU64,
+    pub io_errors: AtomicU64,
+    pub decode_errors: AtomicU64,
+    pub encode_errors: AtomicU64,
+    pub fragment_errors: AtomicU64,
+    pub misdirected: AtomicU64,
+    pub bad_signatures: AtomicU64,
+    pub bad_puzzles: AtomicU64,
+    // responses nobody is waiting for, or coming from someone else than the one asked
+    pub unsolicited: AtomicU64,
+    // encrypted sessions set up, by us or by peers
+    pub handshakes: AtomicU64,
+    pub handshake_failures: AtomicU64,
+    pub session_errors: AtomicU64,
+}
+
 #[derive(Clone, Debug)]
 pub struct Rpc {
-    pub socket: Arc<UdpSocket>,
-    pub pending: Arc<Mutex<HashMap<Key, mpsc::Sender<Option<Response>>>>>,
+    pub transport: Arc<dyn Transport>,
+    pub pending: Arc<Mutex<HashMap<Key, PendingRequest>>>,
     pub node: Node,
+    // signs every message we send, node.id is its id
+    pub identity: Arc<Identity>,
+    // solution of the dynamic puzzle for node.id, sent with every message
+    pub puzzle: Key,
+    // encrypted sessions with peers, see session.rs
+    pub sessions: Arc<Sessions>,
+    // fragments of messages still incomplete, see fragment.rs
+    pub reassembler: Arc<Mutex<Reassembler>>,
+    pub codec: Codec,
+    pub stats: Arc<NetworkStats>,
+    pub config: Arc<ProtocolConfig>,
 }
 
-impl Rpc {
-    pub fn new(node: Node) -> Self {
-        let socket = UdpSocket::bind(node.get_addr())
-            .expect("[FAILED] Rpc::new --> Error while binding UdpSocket to specified addr");
+impl Display for NetworkError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            NetworkError::Io(e) => write!(f, "io error: {}", e),
+            NetworkError::Encode(e) => write!(f, "unable to encode message: {}", e),
+            NetworkError::Decode(e) => write!(f, "unable to decode message: {}", e),
+            NetworkError::Fragment(e) => write!(f, "unable to reassemble message: {}", e),
+            NetworkError::MessageTooLarge(len) => {
+                write!(f, "message of {} bytes is too large", len)
+            }
+            NetworkError::WrongDestination(dst) => write!(f, "message addressed to {}", dst),
+            NetworkError::BadSignature(src) => {
+                write!(f, "message from {} has a bad signature", src)
+            }
+            NetworkError::BadPuzzle(src) => {
+                write!(f, "message from {} doesn't solve the crypto puzzles", src)
+            }
+            NetworkError::Handshake(dst) => write!(f, "no session could be set up with {}", dst),
+            NetworkError::Session(e) => write!(f, "session error: {}", e),
+            NetworkError::ChannelClosed => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
 
+impl From<io::Error> for NetworkError {
+    fn from(e: io::Error) -> Self {
+        NetworkError::Io(e)
+    }
+}
+
+impl NetworkStats {
+    fn record(&self, err: &NetworkError) {
+        let counter = match err {
+            NetworkError::Io(_) => &self.io_errors,
+            NetworkError::Encode(_) | NetworkError::MessageTooLarge(_) => &self.encode_errors,
+            NetworkError::Decode(_) => &self.decode_errors,
+            NetworkError::Fragment(_) => &self.fragment_errors,
+            NetworkError::WrongDestination(_) => &self.misdirected,
+            NetworkError::BadSignature(_) => &self.bad_signatures,
+            NetworkError::BadPuzzle(_) => &self.bad_puzzles,
+            NetworkError::Handshake(_) => &self.handshake_failures,
+            NetworkError::Session(_) => &self.session_errors,
+            NetworkError::ChannelClosed => return,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Rpc {
+    pub fn new(
+        node: Node,
+        identity: Arc<Identity>,
+        transport: Arc<dyn Transport>,
+        codec: Codec,
+        config: Arc<ProtocolConfig>,
+    ) -> Self {
         Self {
-            socket: Arc::new(socket),
+            transport,
             pending: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Sessions::new(
+                identity.clone(),
+                node.get_addr(),
+                config.max_handshakes,
+            )),
+            puzzle: puzzle::solve_dynamic(&node.id, config.dynamic_difficulty),
+            reassembler: Arc::new(Mutex::new(Reassembler::new(config.timeout))),
             node,
+            identity,
+            codec,
+            stats: Arc::new(NetworkStats::default()),
+            config,
         }
     }
-    pub fn open(rpc: Rpc, sender: mpsc::Sender<ReqWrapper>) {
-        thread::spawn(move || {
-            let mut buf = [0u8; BUF_SIZE];
 
-            loop {
-                let (len, src_addr) = rpc
-                    .socket
-                    .recv_from(&mut buf)
-                    .expect("[FAILED] Rpc::open --> Failed to receive data from peer");
+    // receives, reassembles and decodes the next message
+    async fn recv_msg(&self, buf: &mut [u8]) -> Result<Option<RpcMessage>, NetworkError> {
+        let (len, src_addr) = self.transport.recv_from(buf).await?;
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+
+        let message = if fragment::is_fragment(&buf[..len]) {
+            let reassembled = self
+                .reassembler
+                .lock()
+                .expect("[FAILED] Rpc::recv_msg --> Failed to acquire mutex on Reassembler")
+                .accept(&src_addr, &buf[..len]);
+            match reassembled.map_err(NetworkError::Fragment)? {
+                Some(message) => message,
+                // waiting for the other fragments
+                None => return Ok(None),
+            }
+        } else {
+            buf[..len].to_vec()
+        };
 
-                let payload =
-                    String::from(str::from_utf8(&buf[..len]).expect(
-                        "[FAILED] Rpc::open --> Unable to parse string from received bytes",
-                    ));
+        if session::is_handshake(&message) {
+            let accept = self
+                .sessions
+                .handle_handshake(&src_addr, &message)
+                .map_err(NetworkError::Session)?;
+            // the initiator counts the handshake once our Accept arrives
+            if let Some(accept) = accept {
+                self.transport.send_to(&accept, &src_addr).await?;
+                self.stats.handshakes.fetch_add(1, Ordering::Relaxed);
+            }
 
-                let mut decoded: RpcMessage = serde_json::from_str(&payload)
-                    .expect("[FAILED] Rpc::open, serde_json --> Unable to decode string payload");
+            return Ok(None);
+        }
 
-                decoded.src = src_addr.to_string();
+        // plaintext messages are always accepted, whether we send them or not depends on the config
+        let (session, message) = if session::is_sealed(&message) {
+            let (session, plaintext) = self
+                .sessions
+                .open(&src_addr, &message)
+                .map_err(NetworkError::Session)?;
+            (Some(session), plaintext)
+        } else {
+            (None, message)
+        };
+
+        let mut decoded = Codec::decode(&message).map_err(NetworkError::Decode)?;
+        decoded.src = src_addr;
+
+        if let Some(session) = session {
+            if session.peer_id != decoded.src_id {
+                return Err(NetworkError::Session(SessionError::WrongPeer));
+            }
+        }
+
+        if decoded.dst != self.node.get_addr() {
+            return Err(NetworkError::WrongDestination(decoded.dst));
+        }
+
+        // nothing reaches the routing table or the store unless the sender proved its id
+        if !decoded.verify() {
+            return Err(NetworkError::BadSignature(decoded.src));
+        }
+        // ids that are too cheap to make are refused, see puzzle.rs
+        if !puzzle::verify(
+            &decoded.src_id,
+            &decoded.puzzle,
+            self.config.static_difficulty,
+            self.config.dynamic_difficulty,
+        ) {
+            return Err(NetworkError::BadPuzzle(decoded.src));
+        }
 
-                if super::VERBOSE {
+        Ok(Some(decoded))
+    }
+
+    // the receive loop stops once the transport is closed
+    pub fn open(rpc: Rpc, sender: mpsc::UnboundedSender<ReqWrapper>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            // peers may use larger datagrams than we do, see fragment::MAX_DATAGRAM
+            let mut buf = vec![0u8; fragment::MAX_DATAGRAM];
+
+            loop {
+                let decoded = match rpc.recv_msg(&mut buf).await {
+                    Ok(Some(decoded)) => decoded,
+                    Ok(None) => continue,
+                    Err(NetworkError::Io(ref e)) if e.kind() == io::ErrorKind::NotConnected => {
+                        break;
+                    }
+                    Err(e) => {
+                        // a bad datagram must never take the listener down
+                        rpc.stats.record(&e);
+                        eprintln!("[WARNING] Rpc::open --> {}, ignoring", e);
+                        continue;
+                    }
+                };
+
+                if rpc.config.verbose {
                     println!(
                         "----------\n[+] Received message: {:?}\n\ttoken: {:?}\n\tsrc: {}\n\tdst: {}\n\tmsg: {:?}\n----------",
                         &decoded.msg, &decoded.token, &decoded.src, &decoded.dst, &decoded.msg
                     );
                 }
 
-                if decoded.dst != rpc.node.get_addr() {
-                    eprintln!("[WARNING] Rpc::open --> Destination address doesn't match node address, ignoring");
-                    continue;
-                }
-
                 match decoded.msg {
-                    Message::Abort => {
-                        break;
-                    }
                     Message::Request(req) => {
                         let wrapped_req = ReqWrapper {
                             token: decoded.token,
                             src: decoded.src,
+                            src_id: decoded.src_id,
                             payload: req,
                         };
 
-                        if let Err(_) = sender.send(wrapped_req) {
+                        if sender.send(wrapped_req).is_err() {
                             eprintln!("[FAILED] Rpc::open, Request --> Receiver is dead, closing channel.");
                             break;
                         }
                     }
                     Message::Response(res) => {
-                        rpc.clone().handle_response(decoded.token, res);
+                        rpc.handle_response(
+                            decoded.token,
+                            &decoded.src,
+                            ResWrapper {
+                                src_id: decoded.src_id,
+                                payload: res,
+                            },
+                        );
                     }
                 }
             }
-        });
+        })
+    }
+
+    // wakes up everyone waiting for a response, they get NetworkError::ChannelClosed
+    pub fn cancel_pending(&self) {
+        self.pending
+            .lock()
+            .expect("[FAILED] Rpc::cancel_pending --> Failed to acquire mutex on Pending")
+            .clear();
     }
 
-    pub fn send_msg(&self, msg: &RpcMessage) {
-        let encoded = serde_json::to_string(msg)
-            .expect("[FAILED] Rpc::send_msg --> Unable to serialize message");
-        self.socket
-            .send_to(&encoded.as_bytes(), &msg.dst)
-            .expect("[FAILED] Rpc::send_msg --> Error while sending message to specified address");
+    // a message from us, signed with our identity
+    pub fn message(&self, token: Key, dst: String, msg: Message) -> RpcMessage {
+        let mut msg = RpcMessage {
+            token,
+            src: self.node.get_addr(),
+            src_id: self.node.id.clone(),
+            dst,
+            msg,
+            puzzle: self.puzzle.clone(),
+            public_key: self.identity.public_key(),
+            signature: Vec::new(),
+        };
+        msg.signature = self.identity.sign(&msg.signed_bytes());
+
+        msg
     }
 
-    pub fn handle_response(self, token: Key, res: Response) {
-        thread::spawn(move || {
-            let mut pending = self
-                .pending
-                .lock()
-                .expect("[FAILED] Rpc::handle_response --> Failed to acquire lock on Pending");
+    // dst_id is the node expected at msg.dst, None when it's only known by address
+    pub async fn send_msg(
+        &self,
+        msg: &RpcMessage,
+        dst_id: Option<&Key>,
+    ) -> Result<(), NetworkError> {
+        let res = self.try_send_msg(msg, dst_id).await;
+        if let Err(e) = &res {
+            self.stats.record(e);
+        }
 
-            let tmp = match pending.get(&token) {
-                Some(sender) => sender.send(Some(res)),
-                None => {
-                    eprintln!(
-                        "[WARNING] Rpc::handle_response --> Unsolicited response received, ignoring..."
-                    );
-                    return;
-                }
-            };
+        res
+    }
 
-            if let Ok(_) = tmp {
-                pending.remove(&token);
+    // the session used to encrypt messages to dst_id at dst, a handshake is made
+    // when there's none yet with that node
+    async fn session(&self, dst: &str, dst_id: Option<&Key>) -> Result<Arc<Session>, NetworkError> {
+        let expected = |session: &Session| dst_id.is_none_or(|id| *id == session.peer_id);
+        match self.sessions.get(dst) {
+            Some(session) if expected(&session) => return Ok(session),
+            _ => (),
+        }
+
+        let (id, init, receiver) = self.sessions.initiate(dst, dst_id);
+        if let Some(init) = init {
+            self.transport.send_to(&init, dst).await?;
+        }
+
+        match tokio::time::timeout(self.config.timeout, receiver).await {
+            // a handshake already going on may have expected another id
+            Ok(Ok(session)) if expected(&session) => {
+                self.stats.handshakes.fetch_add(1, Ordering::Relaxed);
+                Ok(session)
+            }
+            _ => {
+                self.sessions.abandon(id);
+                Err(NetworkError::Handshake(dst.to_string()))
             }
-        });
+        }
     }
 
-    pub fn make_request(&self, req: Request, dst: Node) -> mpsc::Receiver<Option<Response>> {
-        let (sender, receiver) = mpsc::channel();
+    async fn try_send_msg(
+        &self,
+        msg: &RpcMessage,
+        dst_id: Option<&Key>,
+    ) -> Result<(), NetworkError> {
+        let mut encoded = self.codec.encode(msg).map_err(NetworkError::Encode)?;
+        // messages to ourselves never leave the host
+        if !self.config.plaintext && msg.dst != self.node.get_addr() {
+            encoded = self.session(&msg.dst, dst_id).await?.seal(&encoded);
+        }
+
+        let buf_size = self.config.buf_size;
+        if encoded.len() <= buf_size {
+            self.transport.send_to(&encoded, &msg.dst).await?;
+        } else {
+            // the receiver would truncate it, sending it in pieces
+            let datagrams = fragment::fragment(rand::random(), &encoded, buf_size)
+                .ok_or(NetworkError::MessageTooLarge(encoded.len()))?;
+
+            for datagram in datagrams {
+                self.transport.send_to(&datagram, &msg.dst).await?;
+            }
+        }
+
+        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // wakes up the request waiting for this response, src is the address it came from.
+    // The response must come from the address the request went to, or be signed by
+    // the node it was meant for: knowing the token isn't enough
+    pub fn handle_response(&self, token: Key, src: &str, res: ResWrapper) {
         let mut pending = self
             .pending
             .lock()
-            .expect("[FAILED] Rpc::make_request --> Failed to acquire mutex on Pending");
-
-        let token = Key::new(format!(
-            "{}:{}:{:?}",
-            self.node.get_info(),
-            dst.get_info(),
-            std::time::SystemTime::now()
-        ));
-        pending.insert(token.clone(), sender.clone());
-
-        let msg = RpcMessage {
-            token: token.clone(),
-            src: self.node.get_addr(),
-            dst: dst.get_addr(),
-            msg: Message::Request(req),
+            .expect("[FAILED] Rpc::handle_response --> Failed to acquire lock on Pending");
+        let request = match pending.get(&token) {
+            Some(req) if req.dst == src || req.dst_id == res.src_id => pending.remove(&token),
+            // the request keeps waiting for the real response
+            _ => None,
         };
+        drop(pending);
 
-        self.send_msg(&msg);
-
-        let rpc = self.clone();
-        thread::spawn(move || {
-            thread::sleep(std::time::Duration::from_millis(TIMEOUT));
-            if let Ok(_) = sender.send(None) {
-                let mut pending = rpc
-                    .pending
-                    .lock()
-                    .expect("[FAILED] Rpc::make_request --> Failed to acquire mutex on Pending");
-                pending.remove(&token);
+        match request {
+            // the requester may have given up in the meantime
+            Some(request) => {
+                let _ = request.sender.send(res);
+            }
+            None => {
+                self.stats.unsolicited.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "[WARNING] Rpc::handle_response --> Unsolicited response received from {}, ignoring...",
+                    src
+                );
             }
-        });
+        }
+    }
+
+    // sends req to dst and waits for the response, Ok(None) means that dst didn't answer in time
+    pub async fn make_request(
+        &self,
+        req: Request,
+        dst: Node,
+    ) -> Result<Option<ResWrapper>, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+
+        // rand::random draws from a CSPRNG, the token can't be guessed by who didn't see the request
+        let token = Key::random();
+        self.pending
+            .lock()
+            .expect("[FAILED] Rpc::make_request --> Failed to acquire mutex on Pending")
+            .insert(
+                token.clone(),
+                PendingRequest {
+                    dst: dst.get_addr(),
+                    dst_id: dst.id.clone(),
+                    sender,
+                },
+            );
+
+        let msg = self.message(token.clone(), dst.get_addr(), Message::Request(req));
+
+        let dst_id = if dst.known_by_address() {
+            None
+        } else {
+            Some(&dst.id)
+        };
+        let res = match self.send_msg(&msg, dst_id).await {
+            Ok(()) => match tokio::time::timeout(self.config.timeout, receiver).await {
+                Ok(Ok(res)) => return Ok(Some(res)),
+                Ok(Err(_)) => Err(NetworkError::ChannelClosed),
+                Err(_) => {
+                    // dst may have restarted and lost our session
+                    self.sessions.forget(&dst.get_addr());
+                    Ok(None)
+                }
+            },
+            // dst didn't answer the handshake either
+            Err(NetworkError::Handshake(_)) => Ok(None),
+            Err(e) => Err(e),
+        };
+
+        self.pending
+            .lock()
+            .expect("[FAILED] Rpc::make_request --> Failed to acquire mutex on Pending")
+            .remove(&token);
 
-        receiver
+        res
     }
 }
@@ -1,6 +1,8 @@
 use super::key::Key;
 use serde::{Deserialize, Serialize};
 
+use std::net::{IpAddr, SocketAddr};
+
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct Node {
     pub ip: String,
@@ -9,12 +11,24 @@ pub struct Node {
 }
 
 impl Node {
+    // the id is derived from the address, good enough for a node we only know by address
+    // (e.g. a bootstrap node): the real id is learned as soon as it answers
     pub fn new(ip: String, port: u16) -> Self {
         let addr = format!("{}:{}", ip, port);
         let id = Key::new(addr);
 
         Node { ip, port, id }
     }
+
+    pub fn with_id(ip: String, port: u16, id: Key) -> Self {
+        Node { ip, port, id }
+    }
+
+    // whether the id is the placeholder derived from the address, see new
+    pub fn known_by_address(&self) -> bool {
+        self.id == Node::new(self.ip.clone(), self.port).id
+    }
+
     pub fn get_info(&self) -> String {
         let mut parsed_id = hex::encode(self.id.0);
         parsed_id = parsed_id.to_ascii_uppercase();
@@ -22,7 +36,11 @@ impl Node {
         format!("{}:{}:{}", self.ip, self.port, parsed_id)
     }
 
+    // "ip:port", IPv6 addresses go in brackets as in "[2001:db8::1]:8080"
     pub fn get_addr(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+        match self.ip.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, self.port).to_string(),
+            Err(_) => format!("{}:{}", self.ip, self.port),
+        }
     }
 }
@@ -1,161 +1,766 @@
+use super::codec::Codec;
+use super::config::{ProtocolBuilder, ProtocolConfig};
+use super::error::KademliaError;
+use super::identity::Identity;
+use super::key::{Distance, Key};
+use super::lookup::{DisjointLookup, LookupStats};
 use super::network;
 use super::node::Node;
 use super::routing;
+use super::storage::{self, StorageBackend, StoreEntry};
+use super::transport::Transport;
 use super::utils;
 
-use crossbeam_channel;
-use std::collections::{BinaryHeap, HashMap, HashSet};
-use std::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+// longest wait between two pings of a bootstrap node, see join
+pub const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+// counters about the background jobs of a Protocol
+#[derive(Debug, Default)]
+pub struct ProtocolStats {
+    pub bucket_refreshes: AtomicU64,
+    // pairs sent to new contacts closer to their key than us, or to our neighbors on shutdown
+    pub handed_off: AtomicU64,
+    // pairs put again by republish
+    pub republished: AtomicU64,
+}
 
 #[derive(Debug, Clone)]
 pub struct Protocol {
     pub routes: Arc<Mutex<routing::RoutingTable>>,
-    pub store: Arc<Mutex<HashMap<String, String>>>,
+    pub store: Arc<dyn StorageBackend>,
     pub rpc: Arc<network::Rpc>,
     pub node: Node,
+    pub config: Arc<ProtocolConfig>,
+    pub stats: Arc<ProtocolStats>,
+    // last time pairs were handed off to a contact, see handoff
+    pub handoffs: Arc<Mutex<HashMap<super::key::Key, Instant>>>,
+    // start of the current handoff_cooldown period and the pairs handed off in it
+    pub handoff_budget: Arc<Mutex<(Instant, usize)>>,
+    // held while writing config.contacts_file, see save_contacts
+    pub saving: Arc<Mutex<()>>,
+    // known contacts seen at another address, being pinged there, see add_sender
+    pub moving: Arc<Mutex<HashSet<super::key::Key>>>,
+    // set by shutdown, background jobs stop when it changes
+    pub stopping: Arc<watch::Sender<bool>>,
+    // receive loop, requests handler and background jobs, joined by shutdown
+    pub tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
 }
 
 impl Protocol {
-    pub fn new(ip: String, port: u16, bootstrap: Option<Node>) -> Self {
-        let node = Node::new(ip, port);
+    // uses the default ProtocolConfig, see ProtocolBuilder for the other settings
+    pub async fn new(
+        ip: String,
+        port: u16,
+        bootstrap: Option<Node>,
+    ) -> Result<Self, KademliaError> {
+        let mut builder = ProtocolBuilder::new().bind(ip, port);
+        if let Some(bootstrap) = bootstrap {
+            builder = builder.bootstrap(bootstrap);
+        }
 
-        // channel used for a 2-way communication with the Routing Table module
-        let (rt_channel_sender, rt_channel_receiver) = crossbeam_channel::unbounded();
+        builder.build().await
+    }
 
-        let routes = routing::RoutingTable::new(
+    // must be called from within a tokio runtime, every background job is a task on it.
+    // Fails with BootstrapFailed when there were nodes to join through but none of them answered
+    pub(crate) async fn start(
+        node: Node,
+        identity: Identity,
+        bootstrap: Vec<Node>,
+        transport: Arc<dyn Transport>,
+        codec: Codec,
+        config: ProtocolConfig,
+        store: Arc<dyn StorageBackend>,
+    ) -> Result<Self, KademliaError> {
+        let config = Arc::new(config);
+
+        let routes = routing::RoutingTable::with_limits(
             node.clone(),
-            bootstrap,
-            rt_channel_sender.clone(),
-            rt_channel_receiver.clone(),
+            config.k_param,
+            config.static_difficulty,
+            config.ip_limits,
         );
 
         // 1-way channel to communicate with the Network module
-        let (rpc_channel_sender, rpc_channel_receiver) = mpsc::channel();
+        let (rpc_channel_sender, rpc_channel_receiver) = mpsc::unbounded_channel();
 
-        let rpc = network::Rpc::new(node.clone());
-        network::Rpc::open(rpc.clone(), rpc_channel_sender);
+        let rpc = network::Rpc::new(
+            node.clone(),
+            Arc::new(identity),
+            transport,
+            codec,
+            config.clone(),
+        );
+        let receive_loop = network::Rpc::open(rpc.clone(), rpc_channel_sender);
 
         let protocol = Self {
             routes: Arc::new(Mutex::new(routes)),
-            store: Arc::new(Mutex::new(HashMap::new())),
+            store,
             rpc: Arc::new(rpc),
             node: node.clone(),
+            config,
+            stats: Arc::new(ProtocolStats::default()),
+            handoffs: Arc::new(Mutex::new(HashMap::new())),
+            handoff_budget: Arc::new(Mutex::new((Instant::now(), 0))),
+            saving: Arc::new(Mutex::new(())),
+            moving: Arc::new(Mutex::new(HashSet::new())),
+            stopping: Arc::new(watch::Sender::new(false)),
+            tasks: Arc::new(Mutex::new(vec![receive_loop])),
         };
 
-        protocol.clone().requests_handler(rpc_channel_receiver);
-        protocol
-            .clone()
-            .rt_forwarder(rt_channel_sender, rt_channel_receiver);
+        let handler = protocol.clone().requests_handler(rpc_channel_receiver);
+        protocol.track(handler);
+
+        // warm restart from the contacts saved by a previous run
+        let mut known = protocol.load_contacts().await;
+        protocol.join(&bootstrap).await;
+        known.extend(bootstrap);
+        if !known.is_empty() && protocol.is_isolated() {
+            // the saved contacts are left untouched for the next try
+            protocol.stop().await;
+            return Err(KademliaError::BootstrapFailed(known));
+        }
 
         // performing node lookup on ourselves
-        protocol.nodes_lookup(&node.id);
+        protocol.nodes_lookup(&node.id).await;
+
+        // republishing <key, value> pairs, every publish_interval the ones we published
+        // and every republish_interval the replicas
+        let config = protocol.config.clone();
+        let period = config.publish_interval.min(config.republish_interval);
+        protocol.every(period, |p| async move {
+            p.republish().await;
+        });
 
-        // republishing <key, value> pairs every hour
-        let protocol_clone = protocol.clone();
-        std::thread::spawn(move || {
-            std::thread::sleep(std::time::Duration::from_secs(60 * 60));
-            protocol_clone.republish();
+        // dropping the messages whose fragments stopped coming
+        protocol.every(config.timeout, |p| async move {
+            p.rpc
+                .reassembler
+                .lock()
+                .expect("[FAILED] Protocol::start --> Failed to acquire mutex on Reassembler")
+                .expire();
         });
 
-        protocol
+        // evicting expired <key, value> pairs every sweep_interval
+        protocol.every(config.sweep_interval, |p| async move {
+            p.sweep();
+        });
+
+        // refreshing buckets left idle for refresh_interval
+        protocol.every(config.refresh_interval, |p| async move {
+            p.refresh_buckets().await;
+        });
+
+        // saving the routing table contacts every contacts_save_interval
+        if config.contacts_file.is_some() {
+            protocol.every(config.contacts_save_interval, |p| async move {
+                if let Err(e) = p.save_contacts().await {
+                    eprintln!("[WARNING] Protocol::save_contacts --> {}", e);
+                }
+            });
+        }
+
+        Ok(protocol)
     }
 
-    fn republish(&self) {
-        let st = self
-            .store
-            .lock()
-            .expect("[FAILED] Protocol::republish --> Failed to acquire mutex on Store");
-        for (key, value) in &*st {
-            self.put(key.to_string(), value.to_string());
+    // Stops the node, the address it was bound to can be used again once this returns.
+    // With handoff the stored pairs are first sent to the closest contacts we know,
+    // the contacts are saved (see ProtocolConfig::contacts_file) and the store flushed
+    // in any case. Requests still waiting for a response fail with NetworkError::ChannelClosed
+    pub async fn shutdown(&self, handoff: bool) {
+        if handoff {
+            self.handoff_all().await;
+        }
+        if let Err(e) = self.save_contacts().await {
+            eprintln!("[WARNING] Protocol::shutdown --> {}", e);
+        }
+
+        self.stop().await;
+
+        // nothing changes the store anymore
+        let store = self.store.clone();
+        match tokio::task::spawn_blocking(move || store.flush()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => eprintln!("[WARNING] Protocol::shutdown --> {}", e),
+            Err(e) => eprintln!("[WARNING] Protocol::shutdown --> {}", e),
         }
     }
 
-    // forwards upcoming requests (only Pings at the moment) from the Routing table
-    fn rt_forwarder(
-        self,
-        sender: crossbeam_channel::Sender<utils::ChannelPayload>,
-        receiver: crossbeam_channel::Receiver<utils::ChannelPayload>,
-    ) {
-        std::thread::spawn(move || {
-            for req in receiver.iter() {
+    // stops the background jobs and closes the transport, which ends the receive loop
+    // and then the requests handler. Running jobs are not interrupted
+    async fn stop(&self) {
+        self.stopping.send_replace(true);
+        self.rpc.transport.close();
+        self.rpc.cancel_pending();
+
+        let tasks = std::mem::take(
+            &mut *self
+                .tasks
+                .lock()
+                .expect("[FAILED] Protocol::stop --> Failed to acquire mutex on Tasks"),
+        );
+        for t in tasks {
+            if let Err(e) = t.await {
+                eprintln!("[WARNING] Protocol::stop --> {}", e);
+            }
+        }
+    }
+
+    fn track(&self, task: JoinHandle<()>) {
+        self.tasks
+            .lock()
+            .expect("[FAILED] Protocol::track --> Failed to acquire mutex on Tasks")
+            .push(task);
+    }
+
+    // runs job every period until shutdown
+    fn every<F, Fut>(&self, period: Duration, job: F)
+    where
+        F: Fn(Protocol) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let protocol = self.clone();
+        let mut stopping = self.stopping.subscribe();
+
+        self.track(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(period) => job(protocol.clone()).await,
+                    _ = stopping.changed() => break,
+                }
+            }
+        }));
+    }
+
+    // sends every unexpired pair to the k_param closest contacts we know, with its
+    // remaining TTL. Returns how many pairs at least one of them acknowledged
+    async fn handoff_all(&self) -> usize {
+        let now = SystemTime::now();
+        let mut handed_off = 0;
+
+        for (k, entry) in self.store.entries() {
+            let ttl = match entry.expires_at.duration_since(now) {
+                Ok(ttl) => ttl,
+                Err(_) => continue,
+            };
+            let closest = self
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::handoff_all --> Failed to acquire mutex on Routes")
+                .get_closest_nodes(&k, self.config.k_param);
+
+            let mut joins = Vec::with_capacity(closest.len());
+            for routing::NodeAndDistance(node, _) in closest {
+                if node.id == self.node.id {
+                    continue;
+                }
+
                 let protocol = self.clone();
-                let sender_clone = sender.clone();
-
-                std::thread::spawn(move || match req {
-                    utils::ChannelPayload::Request(payload) => match payload.0 {
-                        network::Request::Ping => {
-                            let success = protocol.ping(payload.1);
-                            if success {
-                                if let Err(_) = sender_clone
-                                    .send(utils::ChannelPayload::Response(network::Response::Ping))
-                                {
-                                    eprintln!("[FAILED] Protocol::rt_forwared --> Receiver is dead, closing channel");
-                                }
-                            } else {
-                                if let Err(_) = sender_clone.send(utils::ChannelPayload::NoData) {
-                                    eprintln!("[FAILED] Protocol::rt_forwared --> Receiver is dead, closing channel");
-                                }
-                            }
+                let (k, v) = (k.clone(), entry.value.clone());
+                joins.push(tokio::spawn(async move {
+                    protocol.store(node, k, v, ttl).await
+                }));
+            }
+
+            let mut acked = false;
+            for j in joins {
+                let res = j.await.expect(
+                    "[FAILED] Protocol::handoff_all --> Failed to join task while storing value",
+                );
+                acked |= res.is_ok();
+            }
+            if acked {
+                handed_off += 1;
+                self.stats.handed_off.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        handed_off
+    }
+
+    // Pings the bootstrap nodes concurrently, retrying with exponential backoff, and returns
+    // as soon as one of them answers, or once all of them gave up. The others keep being
+    // tried in the background until shutdown, the ones that answer end up in the routing table
+    async fn join(&self, bootstrap: &[Node]) -> bool {
+        let (answered_sender, mut answered) = mpsc::unbounded_channel();
+        for node in bootstrap.iter().filter(|n| n.id != self.node.id) {
+            let protocol = self.clone();
+            let node = node.clone();
+            let answered_sender = answered_sender.clone();
+            let mut stopping = self.stopping.subscribe();
+
+            self.track(tokio::spawn(async move {
+                let probe = async {
+                    let mut backoff = protocol.config.bootstrap_backoff.min(MAX_BACKOFF);
+                    for attempt in 0..=protocol.config.bootstrap_retries {
+                        if attempt > 0 {
+                            tokio::time::sleep(backoff).await;
+                            backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
                         }
-                        _ => {
-                            unimplemented!();
+                        if protocol.ping(node.clone()).await.is_ok() {
+                            return true;
                         }
-                    },
-                    utils::ChannelPayload::Response(_) => {
-                        eprintln!("[FAILED] Protocol::rt_forwarder --> Received a Response instead of a Request")
                     }
-                    utils::ChannelPayload::NoData => {
-                        eprintln!("[FAILED] Protocol::rt_forwarder --> Received a NoData instead of a Request")
+
+                    false
+                };
+
+                tokio::select! {
+                    // nobody listens anymore once join returned
+                    ok = probe => { let _ = answered_sender.send(ok); }
+                    _ = stopping.changed() => {}
+                }
+            }));
+        }
+        drop(answered_sender);
+
+        while let Some(ok) = answered.recv().await {
+            if ok {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // whether the routing table has no contact other than us
+    fn is_isolated(&self) -> bool {
+        self.routes
+            .lock()
+            .expect("[FAILED] Protocol::is_isolated --> Failed to acquire mutex on Routes")
+            .contacts()
+            .is_empty()
+    }
+
+    // writes the routing table contacts to config.contacts_file (if any) on tokio's
+    // blocking threads, returns how many were saved. One save at a time, the contacts
+    // are read once it's our turn so that the newest ones land last
+    pub async fn save_contacts(&self) -> io::Result<usize> {
+        let path = match &self.config.contacts_file {
+            Some(path) => path.clone(),
+            None => return Ok(0),
+        };
+
+        let protocol = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _saving = protocol
+                .saving
+                .lock()
+                .expect("[FAILED] Protocol::save_contacts --> Failed to acquire mutex on Saving");
+            let contacts = protocol
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::save_contacts --> Failed to acquire mutex on Routes")
+                .contacts();
+            let json = serde_json::to_vec(&contacts).map_err(io::Error::other)?;
+
+            utils::write_atomically(&path, &json)?;
+            Ok(contacts.len())
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    // seeds the routing table with the contacts in config.contacts_file, least-recently
+    // seen first like in the buckets. They're all pinged, the ones that don't answer are
+    // removed by query. Returns the seeded contacts
+    async fn load_contacts(&self) -> Vec<Node> {
+        let path = match &self.config.contacts_file {
+            Some(path) => path.clone(),
+            None => return Vec::new(),
+        };
+
+        let read = tokio::task::spawn_blocking(move || std::fs::read(path))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|read| read);
+        let mut contacts: Vec<routing::SavedContact> = match read {
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(contacts) => contacts,
+                Err(e) => {
+                    eprintln!("[WARNING] Protocol::load_contacts --> {}", e);
+                    return Vec::new();
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                eprintln!("[WARNING] Protocol::load_contacts --> {}", e);
+                return Vec::new();
+            }
+        };
+        contacts.sort_by_key(|c| c.last_seen);
+
+        let seeded: Vec<Node> = {
+            let mut routes = self
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::load_contacts --> Failed to acquire mutex on Routes");
+
+            contacts
+                .into_iter()
+                .filter(|c| routes.seed(c.clone()))
+                .map(|c| c.node)
+                .collect()
+        };
+
+        let mut joins = Vec::with_capacity(seeded.len());
+        for node in seeded.iter().cloned() {
+            let protocol = self.clone();
+            joins.push(tokio::spawn(async move { protocol.ping(node).await }));
+        }
+
+        for j in joins {
+            // failed pings already removed the contact
+            let _ = j
+                .await
+                .expect("[FAILED] Protocol::load_contacts --> Failed to join task while pinging");
+        }
+
+        seeded
+    }
+
+    // runs a lookup on a random id of every stale bucket, returns how many were refreshed
+    pub async fn refresh_buckets(&self) -> usize {
+        let targets: Vec<super::key::Key> = {
+            let routes = self
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::refresh_buckets --> Failed to acquire mutex on Routes");
+
+            routes
+                .stale_buckets(self.config.refresh_interval)
+                .into_iter()
+                .map(|i| routes.random_id_in_bucket(i))
+                .collect()
+        };
+
+        for target in &targets {
+            self.nodes_lookup(target).await;
+            self.stats.bucket_refreshes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        targets.len()
+    }
+
+    // removes the expired entries from the store, returns how many were removed
+    pub fn sweep(&self) -> usize {
+        let now = SystemTime::now();
+        let mut removed = 0;
+
+        for (k, entry) in self.store.entries() {
+            if entry.publishing(now) || entry.expires_at > now {
+                continue;
+            }
+            match self.store.delete(&k) {
+                Ok(_) => removed += 1,
+                Err(e) => eprintln!("[WARNING] Protocol::sweep --> {}", e),
+            }
+        }
+
+        removed
+    }
+
+    // puts again the pairs we published every publish_interval, with a fresh ttl, and the
+    // replicas every republish_interval, with their remaining ttl. As in the paper, a replica
+    // that got a Store within republish_interval isn't republished, since another node did it.
+    // Returns how many pairs were republished
+    pub async fn republish(&self) -> usize {
+        let now = SystemTime::now();
+        let elapsed = |t: SystemTime| now.duration_since(t).unwrap_or_default();
+        let entries: Vec<(Key, Vec<u8>, Option<SystemTime>, Duration)> = self
+            .store
+            .entries()
+            .into_iter()
+            .filter_map(|(k, mut entry)| {
+                // past publish_until the pair is just another replica
+                entry.publisher = entry.publishing(now);
+                let ttl = if entry.publisher {
+                    if elapsed(entry.last_republished) < self.config.publish_interval {
+                        return None;
+                    }
+                    self.config.ttl
+                } else {
+                    let last = entry.last_stored.max(entry.last_republished);
+                    if elapsed(last) < self.config.republish_interval {
+                        return None;
                     }
-                });
+                    entry.expires_at.duration_since(now).ok()?
+                };
+
+                entry.last_republished = now;
+                let publish_until = entry.publish_until.filter(|_| entry.publisher);
+                let value = entry.value.clone();
+                if let Err(e) = self.store.put(k.clone(), entry) {
+                    eprintln!("[WARNING] Protocol::republish --> {}", e);
+                }
+                Some((k, value, publish_until, ttl))
+            })
+            .collect();
+
+        let mut republished = 0;
+        for (key, value, publish_until, ttl) in entries {
+            let res = self.replicate(key.clone(), value.clone(), ttl).await;
+            // unless unpublish was called meanwhile
+            let publishing = self.store.get(&key).is_some_and(|e| e.publisher);
+            if let (Some(until), true) = (publish_until, publishing) {
+                self.mark_published(key, value, until);
+            }
+            match res {
+                Ok(_) => {
+                    republished += 1;
+                    self.stats.republished.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => eprintln!("[WARNING] Protocol::republish --> {}", e),
+            }
+        }
+
+        republished
+    }
+
+    // keeps our own copy of a pair we (re)published, so that republish finds it
+    fn mark_published(&self, k: Key, v: Vec<u8>, publish_until: SystemTime) {
+        let mut entry = StoreEntry::new(v, self.config.ttl);
+        entry.publisher = true;
+        entry.publish_until = Some(publish_until);
+        if let Some(old) = self.store.get(&k) {
+            entry.last_stored = old.last_stored;
+        }
+
+        if let Err(e) = self.store.put(k, entry) {
+            eprintln!("[WARNING] Protocol::mark_published --> {}", e);
+        }
+    }
+
+    // inserts or refreshes a contact. When its bucket is full the contact waits in the
+    // replacement cache while the least-recently seen one is pinged in the background,
+    // without holding the lock on Routes
+    fn add_contact(&self, node: Node) {
+        let (is_new, lrs) = {
+            let mut routes = self
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::add_contact --> Failed to acquire mutex on Routes");
+
+            let is_new = !routes.contains(&node.id);
+            let lrs = routes.try_update(node.clone());
+            (is_new && routes.contains(&node.id), lrs)
+        };
+
+        if is_new && node.id != self.node.id {
+            self.handoff(node);
+        }
+
+        if let Some(lrs) = lrs {
+            let protocol = self.clone();
+
+            tokio::spawn(async move {
+                // someone else answering at the same address doesn't count
+                let alive = matches!(
+                    protocol
+                        .rpc
+                        .make_request(network::Request::Ping, lrs.clone())
+                        .await,
+                    Ok(Some(network::ResWrapper {
+                        src_id,
+                        payload: network::Response::Ping,
+                    })) if src_id == lrs.id
+                );
+
+                protocol
+                    .routes
+                    .lock()
+                    .expect("[FAILED] Protocol::add_contact --> Failed to acquire mutex on Routes")
+                    .ping_result(&lrs, alive);
+            });
+        }
+    }
+
+    // The address of a request is just the one its datagram came from, and a signed request
+    // can be replayed from anywhere: a known contact showing up at another address is only
+    // moved there once it answers a ping sent to that address
+    fn add_sender(&self, node: Node) {
+        let known = self
+            .routes
+            .lock()
+            .expect("[FAILED] Protocol::add_sender --> Failed to acquire mutex on Routes")
+            .get(&node.id)
+            .map(|x| x.get_addr());
+        match known {
+            Some(addr) if addr != node.get_addr() => {}
+            _ => return self.add_contact(node),
+        }
+
+        // one check at a time per contact
+        if !self
+            .moving
+            .lock()
+            .expect("[FAILED] Protocol::add_sender --> Failed to acquire mutex on Moving")
+            .insert(node.id.clone())
+        {
+            return;
+        }
+
+        let protocol = self.clone();
+        tokio::spawn(async move {
+            // only the holder of the id's key can sign the answer
+            let moved = matches!(
+                protocol
+                    .rpc
+                    .make_request(network::Request::Ping, node.clone())
+                    .await,
+                Ok(Some(network::ResWrapper {
+                    src_id,
+                    payload: network::Response::Ping,
+                })) if src_id == node.id
+            );
+            protocol
+                .moving
+                .lock()
+                .expect("[FAILED] Protocol::add_sender --> Failed to acquire mutex on Moving")
+                .remove(&node.id);
+
+            if moved {
+                protocol.add_contact(node);
+            }
+        });
+    }
+
+    // As in the paper, a new contact closer than us to some of our keys gets a copy of them
+    // (with their remaining TTL), from us only if no other contact we know is closer.
+    // At most handoff_max_keys pairs are sent, and a contact doesn't get anything for
+    // handoff_cooldown after a handoff. All contacts together get at most
+    // handoff_max_stores pairs every handoff_cooldown
+    fn handoff(&self, node: Node) {
+        {
+            let mut handoffs = self
+                .handoffs
+                .lock()
+                .expect("[FAILED] Protocol::handoff --> Failed to acquire mutex on Handoffs");
+
+            let cooldown = self.config.handoff_cooldown;
+            handoffs.retain(|_, last| last.elapsed() < cooldown);
+            if handoffs.contains_key(&node.id) {
+                return;
+            }
+            handoffs.insert(node.id.clone(), Instant::now());
+        }
+
+        let now = SystemTime::now();
+        let entries = self.store.entries();
+        let mut pairs: Vec<(Key, Vec<u8>, Duration)> = {
+            let routes = self
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::handoff --> Failed to acquire mutex on Routes");
+            // node itself is one of the contacts closer than us, unless it was dropped meanwhile
+            let others = usize::from(routes.contains(&node.id));
+
+            entries
+                .into_iter()
+                .filter(|(k, _)| {
+                    Distance::new(&node.id, k) < Distance::new(&self.node.id, k)
+                        && routes.count_closer(k) <= others
+                })
+                .filter_map(|(k, entry)| {
+                    let ttl = entry.expires_at.duration_since(now).ok()?;
+                    Some((k, entry.value, ttl))
+                })
+                .take(self.config.handoff_max_keys)
+                .collect()
+        };
+
+        {
+            let mut budget = self
+                .handoff_budget
+                .lock()
+                .expect("[FAILED] Protocol::handoff --> Failed to acquire mutex on HandoffBudget");
+            if budget.0.elapsed() >= self.config.handoff_cooldown {
+                *budget = (Instant::now(), 0);
+            }
+            let left = self.config.handoff_max_stores.saturating_sub(budget.1);
+            pairs.truncate(left);
+            budget.1 += pairs.len();
+        }
+
+        if pairs.is_empty() {
+            return;
+        }
+
+        let protocol = self.clone();
+        tokio::spawn(async move {
+            for (k, v, ttl) in pairs {
+                if let Err(e) = protocol.store(node.clone(), k, v, ttl).await {
+                    eprintln!("[WARNING] Protocol::handoff --> {}", e);
+                    break;
+                }
+                protocol.stats.handed_off.fetch_add(1, Ordering::Relaxed);
             }
         });
     }
 
     // handles requests by crafting responses and sending them
-    fn requests_handler(self, receiver: mpsc::Receiver<network::ReqWrapper>) {
-        std::thread::spawn(move || {
-            for req in receiver.iter() {
+    // stops once the receive loop of Rpc is gone
+    fn requests_handler(
+        self,
+        mut receiver: mpsc::UnboundedReceiver<network::ReqWrapper>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(req) = receiver.recv().await {
                 let protocol = self.clone();
 
-                std::thread::spawn(move || {
+                tokio::spawn(async move {
                     let res = protocol.craft_res(req);
-                    protocol.reply(res);
+                    protocol.reply(res).await;
                 });
             }
-        });
+        })
     }
 
     fn craft_res(&self, req: network::ReqWrapper) -> (network::Response, network::ReqWrapper) {
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Routes");
-
-        // must craft node object because ReqWrapper contains only the src string addr
-        let split = req.src.split(":");
-        let parsed: Vec<&str> = split.collect();
-
-        let src_node = Node::new(
-            parsed[0].to_string(),
-            parsed[1]
-                .parse::<u16>()
-                .expect("[FAILED] Protocol::craft_res --> Failed to parse Node port from address"),
-        );
-        routes.update(src_node);
-        drop(routes);
+        // the address is the one the datagram came from, the id is the one the sender carries
+        match utils::parse_addr(&req.src) {
+            Some((ip, port)) => {
+                self.add_sender(Node::with_id(ip, port, req.src_id.clone()));
+            }
+            None => eprintln!(
+                "[WARNING] Protocol::craft_res --> Unable to parse address {}, not adding the sender",
+                req.src
+            ),
+        }
 
         match req.payload {
             network::Request::Ping => (network::Response::Ping, req),
-            network::Request::Store(ref k, ref v) => {
-                // ref is used to borrow k and v, which are the contents of req
-
-                let mut store = self
-                    .store
-                    .lock()
-                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Store");
-                store.insert(k.to_string(), v.to_string());
+            network::Request::Store(ref k, ref v, ttl) => {
+                // ref is used to borrow k and v, which are the contents of req.
+                // The ttl comes from the peer, it can't be longer than ours
+                let ttl = self.scaled_ttl(k, ttl.min(self.config.ttl));
+
+                let mut entry = StoreEntry::new(v.clone(), ttl);
+                if let Some(old) = self.store.get(k) {
+                    // a Store from someone else doesn't make us any less the publisher
+                    entry.publisher = old.publisher;
+                    entry.publish_until = old.publish_until;
+                    entry.last_republished = old.last_republished;
+                    if old.publisher {
+                        entry.expires_at = entry.expires_at.max(old.expires_at);
+                    }
+                }
+                if let Err(e) = self.store.put(k.clone(), entry) {
+                    eprintln!(
+                        "[WARNING] Protocol::craft_res --> Unable to store value: {}",
+                        e
+                    );
+                }
 
                 (network::Response::Ping, req)
             }
@@ -165,24 +770,20 @@ impl Protocol {
                     .lock()
                     .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Routes");
 
-                let result = routes.get_closest_nodes(id, super::K_PARAM);
+                let result = routes.get_closest_nodes(id, self.config.k_param);
 
                 (network::Response::FindNode(result), req)
             }
             network::Request::FindValue(ref k) => {
-                let key = super::key::Key::new(k.to_string());
-                let store = self
-                    .store
-                    .lock()
-                    .expect("[FAILED] Protocol::craft_res --> Failed to acquire mutex on Store");
-
-                let val = store.get(k);
+                // expired entries may still be there, waiting for the sweeper
+                let val = self.store.get(k).and_then(|entry| {
+                    let ttl = entry.expires_at.duration_since(SystemTime::now()).ok()?;
+                    Some((entry.value, ttl))
+                });
 
                 match val {
-                    Some(v) => (
-                        network::Response::FindValue(routing::FindValueResult::Value(
-                            v.to_string(),
-                        )),
+                    Some((v, ttl)) => (
+                        network::Response::FindValue(routing::FindValueResult::Value(v, ttl)),
                         req,
                     ),
                     None => {
@@ -191,7 +792,7 @@ impl Protocol {
                         );
                         (
                             network::Response::FindValue(routing::FindValueResult::Nodes(
-                                routes.get_closest_nodes(&key, super::K_PARAM),
+                                routes.get_closest_nodes(k, self.config.k_param),
                             )),
                             req,
                         )
@@ -201,266 +802,384 @@ impl Protocol {
         }
     }
 
-    fn reply(&self, packet_details: (network::Response, network::ReqWrapper)) {
-        let msg = network::RpcMessage {
-            token: packet_details.1.token,
-            src: self.node.get_addr(),
-            dst: packet_details.1.src,
-            msg: network::Message::Response(packet_details.0),
-        };
-
-        self.rpc.send_msg(&msg);
-    }
-
-    pub fn ping(&self, dst: Node) -> bool {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::Ping, dst.clone());
-
-        let mut routes = self
+    // As in the paper, the TTL shrinks exponentially with the number of nodes we know
+    // to be closer to the key beyond the k_param closest ones (which get the whole TTL):
+    // far away replicas (e.g. cached by get) don't linger in the network
+    fn scaled_ttl(&self, k: &Key, ttl: Duration) -> Duration {
+        let closer = self
             .routes
             .lock()
-            .expect("[FAILED] Protocol::ping --> Failed to acquire lock on Routes");
+            .expect("[FAILED] Protocol::scaled_ttl --> Failed to acquire mutex on Routes")
+            .count_closer(k);
 
-        if let Some(network::Response::Ping) = res {
-            routes.update(dst);
-            true
-        } else {
-            eprintln!(
-                "[WARNING] Protocol::Ping --> No response, removing contact from routing table"
-            );
-            routes.remove(&dst);
-            false
-        }
+        let shift = closer.saturating_sub(self.config.k_param);
+        ttl / 2u32.saturating_pow(std::cmp::min(shift, 31) as u32)
     }
 
-    pub fn store(&self, dst: Node, key: String, val: String) -> bool {
-        let res =
-            utils::make_req_get_res(&self.rpc, network::Request::Store(key, val), dst.clone());
+    async fn reply(&self, packet_details: (network::Response, network::ReqWrapper)) {
+        let (res, req) = packet_details;
+        let msg = self
+            .rpc
+            .message(req.token, req.src, network::Message::Response(res));
 
-        // since we get a ping, update our routing table
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::store --> Failed to acquire mutex on Routes");
-        if let Some(network::Response::Ping) = res {
-            routes.update(dst);
-            true
-        } else {
-            routes.remove(&dst);
-            false
+        // the request came signed by src_id, the answer is for it only
+        if let Err(e) = self.rpc.send_msg(&msg, Some(&req.src_id)).await {
+            eprintln!("[FAILED] Protocol::reply --> {}", e);
         }
     }
 
-    pub fn find_node(
+    // sends req to dst and updates the routing table depending on the outcome,
+    // extract picks the expected kind of Response.
+    // The contact added is the node that answered, with the id it sent. An answer under
+    // another id than dst.id is an UnexpectedResponse, anyone can hand out made up ids
+    // at their own address, unless dst is only known by address (see Node::new)
+    async fn query<T>(
         &self,
+        req: network::Request,
         dst: Node,
-        id: super::key::Key,
-    ) -> Option<Vec<routing::NodeAndDistance>> {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::FindNode(id), dst.clone());
+        extract: impl FnOnce(network::Response) -> Option<T>,
+    ) -> Result<T, KademliaError> {
+        let res = match self.rpc.make_request(req, dst.clone()).await {
+            Ok(Some(res)) if res.src_id != dst.id && !dst.known_by_address() => {
+                Err(KademliaError::UnexpectedResponse(dst.clone()))
+            }
+            Ok(Some(res)) => {
+                let responder = Node::with_id(dst.ip.clone(), dst.port, res.src_id);
+                extract(res.payload)
+                    .map(|val| (val, responder))
+                    .ok_or_else(|| KademliaError::UnexpectedResponse(dst.clone()))
+            }
+            Ok(None) => Err(KademliaError::Timeout(dst.clone())),
+            Err(e) => Err(e.into()),
+        };
 
         let mut routes = self
             .routes
             .lock()
-            .expect("[FAILED] Protocol::find_node --> Failed to acquire mutex on Routes");
-        if let Some(network::Response::FindNode(entries)) = res {
-            routes.update(dst);
-            Some(entries)
-        } else {
-            routes.remove(&dst);
-            None
+            .expect("[FAILED] Protocol::query --> Failed to acquire mutex on Routes");
+        match res {
+            Ok((val, responder)) => {
+                if responder.id != dst.id && routes.contains(&dst.id) {
+                    routes.remove(&dst);
+                }
+                drop(routes);
+
+                self.add_contact(responder);
+                Ok(val)
+            }
+            Err(e) => {
+                routes.remove(&dst);
+                Err(e)
+            }
         }
     }
 
-    pub fn find_value(&self, dst: Node, k: String) -> Option<routing::FindValueResult> {
-        let res = utils::make_req_get_res(&self.rpc, network::Request::FindValue(k), dst.clone());
+    pub async fn ping(&self, dst: Node) -> Result<(), KademliaError> {
+        let res = self
+            .query(network::Request::Ping, dst, |res| match res {
+                network::Response::Ping => Some(()),
+                _ => None,
+            })
+            .await;
 
-        let mut routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::find_value --> Failed to acquire mutex on Routes");
-
-        if let Some(network::Response::FindValue(val)) = res {
-            routes.update(dst);
-            Some(val)
-        } else {
-            routes.remove(&dst);
-            None
+        if let Err(e) = &res {
+            eprintln!(
+                "[WARNING] Protocol::Ping --> {}, removing contact from routing table",
+                e
+            );
         }
+
+        res
+    }
+
+    // the receiver may shorten the TTL, see scaled_ttl
+    pub async fn store(
+        &self,
+        dst: Node,
+        key: Key,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), KademliaError> {
+        // a Store is acknowledged with a Ping
+        self.query(
+            network::Request::Store(key, val, ttl),
+            dst,
+            |res| match res {
+                network::Response::Ping => Some(()),
+                _ => None,
+            },
+        )
+        .await
     }
 
-    pub fn nodes_lookup(&self, id: &super::key::Key) -> Vec<routing::NodeAndDistance> {
-        let mut ret: Vec<routing::NodeAndDistance> = Vec::new();
+    pub async fn find_node(
+        &self,
+        dst: Node,
+        id: super::key::Key,
+    ) -> Result<Vec<routing::NodeAndDistance>, KademliaError> {
+        self.query(network::Request::FindNode(id), dst, |res| match res {
+            network::Response::FindNode(entries) => Some(entries),
+            _ => None,
+        })
+        .await
+    }
 
-        // nodes visited
-        let mut queried = HashSet::new();
-        let routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::nodes_lookup --> Failed to acquire mutex on Routes");
+    pub async fn find_value(
+        &self,
+        dst: Node,
+        k: Key,
+    ) -> Result<routing::FindValueResult, KademliaError> {
+        self.query(network::Request::FindValue(k), dst, |res| match res {
+            network::Response::FindValue(val) => Some(val),
+            _ => None,
+        })
+        .await
+    }
 
-        // nodes to visit
-        let mut to_query = BinaryHeap::from(routes.get_closest_nodes(id, super::K_PARAM));
-        drop(routes);
+    pub async fn nodes_lookup(&self, id: &super::key::Key) -> Vec<routing::NodeAndDistance> {
+        self.lookup_nodes(id).await.0
+    }
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
-        }
+    // nodes_lookup together with its stats, they tell how well the disjoint paths agree
+    pub async fn lookup_nodes(
+        &self,
+        id: &super::key::Key,
+    ) -> (Vec<routing::NodeAndDistance>, LookupStats) {
+        let lookup = self.run_nodes_lookup(id).await;
+        let stats = lookup.stats();
+        (lookup.result(), stats)
+    }
 
-        while !to_query.is_empty() {
-            // threads joins
-            let mut joins: Vec<std::thread::JoinHandle<Option<Vec<routing::NodeAndDistance>>>> =
-                Vec::new();
-            // outgoing queries
-            let mut queries: Vec<routing::NodeAndDistance> = Vec::new();
-            let mut results: Vec<Option<Vec<routing::NodeAndDistance>>> = Vec::new();
+    // seeds a lookup on config.disjoint_paths paths with the closest nodes we know
+    fn start_lookup(&self, target: &Key) -> DisjointLookup {
+        let seeds = {
+            let mut routes = self
+                .routes
+                .lock()
+                .expect("[FAILED] Protocol::start_lookup --> Failed to acquire mutex on Routes");
+            routes.touch(target);
+            routes.get_closest_nodes(target, self.config.k_param)
+        };
 
-            for _ in 0..super::ALPHA {
-                match to_query.pop() {
-                    Some(entry) => {
-                        queries.push(entry);
-                    }
-                    None => {
-                        break;
-                    }
-                }
-            }
+        DisjointLookup::new(
+            target,
+            seeds,
+            self.config.disjoint_paths,
+            self.config.k_param,
+            self.config.alpha,
+        )
+    }
+
+    async fn run_nodes_lookup(&self, id: &super::key::Key) -> DisjointLookup {
+        let mut lookup = self.start_lookup(id);
+
+        while !lookup.is_done() {
+            // tasks joins
+            let mut joins: Vec<
+                tokio::task::JoinHandle<Result<Vec<routing::NodeAndDistance>, KademliaError>>,
+            > = Vec::new();
+            // outgoing queries of every path
+            let queries = lookup.next_round();
+            let mut results: Vec<Result<Vec<routing::NodeAndDistance>, KademliaError>> = Vec::new();
 
-            for &routing::NodeAndDistance(ref node, _) in &queries {
+            for (_, routing::NodeAndDistance(node, _)) in &queries {
                 let n = node.clone();
                 let id_clone = id.clone();
                 let protocol_clone = self.clone();
 
-                joins.push(std::thread::spawn(move || {
-                    protocol_clone.find_node(n, id_clone)
+                joins.push(tokio::spawn(async move {
+                    protocol_clone.find_node(n, id_clone).await
                 }));
             }
 
             for j in joins {
-                results.push(j.join().expect(
-                    "[FAILED] Protocol::nodes_lookup --> Failed to join thread while visiting nodes",
+                results.push(j.await.expect(
+                    "[FAILED] Protocol::nodes_lookup --> Failed to join task while visiting nodes",
                 ));
             }
 
-            for (result, query) in results.into_iter().zip(queries) {
-                if let Some(entries) = result {
-                    ret.push(query);
-
-                    for entry in entries {
-                        if queried.insert(entry.clone()) {
-                            to_query.push(entry);
-                        }
-                    }
+            for (result, (path, query)) in results.into_iter().zip(queries) {
+                match result {
+                    Ok(entries) => lookup.add_response(path, query, entries),
+                    Err(_) => lookup.add_failure(path, query),
                 }
             }
         }
 
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
-        ret.truncate(super::K_PARAM);
-
-        ret
+        lookup
     }
 
-    pub fn value_lookup(&self, k: String) -> (Option<String>, Vec<routing::NodeAndDistance>) {
-        // NOTE: k and key are two different things, one is a string used to search for the corresponding value while the other is a key::Key
-
-        let mut ret: Vec<routing::NodeAndDistance> = Vec::new();
-        let key = super::key::Key::new(k.clone());
-        let mut queried = HashSet::new();
-
-        let routes = self
-            .routes
-            .lock()
-            .expect("[FAILED] Protocol::value_lookup --> Failed to acquire mutex on Routes");
-        let mut to_query = BinaryHeap::from(routes.get_closest_nodes(&key, super::K_PARAM));
-        drop(routes);
+    pub async fn value_lookup(
+        &self,
+        k: impl Into<Key>,
+    ) -> (Option<Vec<u8>>, Vec<routing::NodeAndDistance>) {
+        let (val, nodes, _) = self.lookup_value(k.into()).await;
+        (val.map(|(v, _)| v), nodes)
+    }
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
-        }
+    // value_lookup together with the ttl of the value and the stats of the lookup.
+    // With disjoint paths the value found by most of them wins
+    pub async fn lookup_value(
+        &self,
+        key: Key,
+    ) -> (
+        Option<(Vec<u8>, Duration)>,
+        Vec<routing::NodeAndDistance>,
+        LookupStats,
+    ) {
+        let lookup = self.run_value_lookup(&key).await;
+        let val = lookup.value().map(|(val, ttl, _)| (val, ttl));
+        let stats = lookup.stats();
+        (val, lookup.result(), stats)
+    }
 
-        while !to_query.is_empty() {
-            let mut joins: Vec<std::thread::JoinHandle<Option<routing::FindValueResult>>> =
-                Vec::new();
-            let mut queries: Vec<routing::NodeAndDistance> = Vec::new();
-            let mut results: Vec<Option<routing::FindValueResult>> = Vec::new();
+    async fn run_value_lookup(&self, key: &Key) -> DisjointLookup {
+        let mut lookup = self.start_lookup(key);
 
-            for _ in 0..super::ALPHA {
-                match to_query.pop() {
-                    Some(entry) => {
-                        queries.push(entry);
-                    }
-                    None => {
-                        break;
-                    }
-                }
-            }
+        while !lookup.is_done() {
+            let mut joins: Vec<
+                tokio::task::JoinHandle<Result<routing::FindValueResult, KademliaError>>,
+            > = Vec::new();
+            let queries = lookup.next_round();
+            let mut results: Vec<Result<routing::FindValueResult, KademliaError>> = Vec::new();
 
-            for &routing::NodeAndDistance(ref n, _) in &queries {
-                let k_clone = k.clone();
+            for (_, routing::NodeAndDistance(n, _)) in &queries {
+                let key_clone = key.clone();
                 let node = n.clone();
                 let protocol = self.clone();
 
-                joins.push(std::thread::spawn(move || {
-                    protocol.find_value(node, k_clone)
+                joins.push(tokio::spawn(async move {
+                    protocol.find_value(node, key_clone).await
                 }));
             }
 
             for j in joins {
-                results.push(j.join().expect("[FAILED] Protocol::value_lookup --> Failed to join thread while searching for value"));
-            }
-
-            for (result, query) in results.into_iter().zip(queries) {
-                if let Some(find_value_result) = result {
-                    match find_value_result {
-                        routing::FindValueResult::Nodes(entries) => {
-                            // we didn't get the value we looked for
-                            ret.push(query);
-                            for entry in entries {
-                                if queried.insert(entry.clone()) {
-                                    to_query.push(entry);
-                                }
-                            }
-                        }
-
-                        routing::FindValueResult::Value(val) => {
-                            ret.sort_by(|a, b| a.1.cmp(&b.1));
-                            ret.truncate(super::K_PARAM);
+                results.push(j.await.expect("[FAILED] Protocol::value_lookup --> Failed to join task while searching for value"));
+            }
 
-                            return (Some(val), ret);
-                        }
+            for (result, (path, query)) in results.into_iter().zip(queries) {
+                match result {
+                    Ok(routing::FindValueResult::Nodes(entries)) => {
+                        // we didn't get the value we looked for
+                        lookup.add_response(path, query, entries);
                     }
+                    Ok(routing::FindValueResult::Value(val, ttl)) => {
+                        // the path is over
+                        lookup.add_value(path, query, val, ttl);
+                    }
+                    Err(_) => lookup.add_failure(path, query),
                 }
             }
         }
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
-        ret.truncate(super::K_PARAM);
-        (None, ret)
+
+        lookup
     }
 
-    pub fn put(&self, k: String, v: String) {
-        let candidates = self.nodes_lookup(&super::key::Key::new(k.clone()));
+    // stores the pair on the k_param closest nodes (of every disjoint path) for config.ttl,
+    // returns how many acknowledged it.
+    // We become its publisher, republishing it every publish_interval for publish_lifetime
+    // strings are hashed into keys, raw keys are used as they are
+    pub async fn put(
+        &self,
+        k: impl Into<Key>,
+        v: impl Into<Vec<u8>>,
+    ) -> Result<usize, KademliaError> {
+        let (k, v) = (k.into(), v.into());
+        let res = self.replicate(k.clone(), v.clone(), self.config.ttl).await;
+        if res.is_ok() {
+            let now = SystemTime::now();
+            let lifetime = self.config.publish_lifetime.min(storage::MAX_TTL);
+            self.mark_published(k, v, now + lifetime);
+        }
+
+        res
+    }
+
+    // stops republishing a pair we put, every copy (ours included) expires with its ttl.
+    // Returns whether we were publishing it
+    pub fn unpublish(&self, k: impl Into<Key>) -> bool {
+        let k = k.into();
+        let mut entry = match self.store.get(&k) {
+            Some(entry) if entry.publisher => entry,
+            _ => return false,
+        };
+
+        entry.publisher = false;
+        entry.publish_until = None;
+        if let Err(e) = self.store.put(k, entry) {
+            eprintln!("[WARNING] Protocol::unpublish --> {}", e);
+        }
 
+        true
+    }
+
+    // stores the pair on the k_param closest nodes of every disjoint path,
+    // returns how many acknowledged it
+    async fn replicate(&self, k: Key, v: Vec<u8>, ttl: Duration) -> Result<usize, KademliaError> {
+        let paths = self.run_nodes_lookup(&k).await;
+        let lookup = paths.stats();
+        let candidates: Vec<routing::NodeAndDistance> =
+            paths.path_results().into_iter().flatten().collect();
+        let replicas = candidates.len();
+
+        let mut joins = Vec::with_capacity(replicas);
         for routing::NodeAndDistance(node, _) in candidates {
             let protocol_clone = self.clone();
             let k_clone = k.clone();
             let v_clone = v.clone();
 
-            std::thread::spawn(move || {
-                protocol_clone.store(node, k_clone, v_clone);
+            joins.push(tokio::spawn(async move {
+                protocol_clone.store(node, k_clone, v_clone, ttl).await
+            }));
+        }
+
+        let mut acked = 0;
+        for j in joins {
+            let res = j
+                .await
+                .expect("[FAILED] Protocol::replicate --> Failed to join task while storing value");
+            if res.is_ok() {
+                acked += 1;
+            }
+        }
+
+        if acked == 0 {
+            return Err(KademliaError::PutFailed {
+                acked,
+                replicas,
+                lookup,
             });
         }
-    }
 
-    pub fn get(&self, k: String) -> Option<String> {
-        let (val, mut nodes) = self.value_lookup(k.clone());
+        Ok(acked)
+    }
 
-        val.map(|v| {
-            if let Some(routing::NodeAndDistance(target, _)) = nodes.pop() {
-                self.store(target, k, v.clone());
-            } else {
-                self.store(self.node.clone(), k, v.clone());
+    pub async fn get(&self, k: impl Into<Key>) -> Result<Vec<u8>, KademliaError> {
+        let k = k.into();
+        let lookup = self.run_value_lookup(&k).await;
+
+        let (v, ttl) = match lookup.value() {
+            Some((v, ttl, _)) => (v, ttl),
+            None => {
+                let stats = lookup.stats();
+                return Err(if stats.responded == 0 {
+                    KademliaError::Unreachable(stats)
+                } else {
+                    KademliaError::NotFound(stats)
+                });
             }
+        };
 
-            v
-        })
+        // caching the pair is best effort, the value was found anyway.
+        // The copy must not outlive the original
+        let target = match lookup.cache_target() {
+            Some(routing::NodeAndDistance(target, _)) => target.clone(),
+            None => self.node.clone(),
+        };
+        if let Err(e) = self.store(target, k, v.clone(), ttl).await {
+            eprintln!("[WARNING] Protocol::get --> Unable to cache value: {}", e);
+        }
+
+        Ok(v)
     }
 }
@@ -0,0 +1,55 @@
+use super::key::{Distance, Key};
+
+use sha2::{Digest, Sha256};
+
+/*
+    S/Kademlia crypto puzzles, they make node ids expensive so that an attacker
+    can't cheaply place nodes next to a key:
+
+    - static:  sha256(id) starts with static_difficulty zero bits. Since the id is the hash
+               of a public key (see identity.rs), the only way is generating keypairs
+               until one fits
+    - dynamic: sha256(id XOR x) starts with dynamic_difficulty zero bits, x is carried in
+               every message. It can be raised over time without changing the ids
+
+    Both difficulties are network parameters: every node must use the same ones.
+*/
+
+// anything harder would keep a node from starting for a long time
+pub const MAX_DIFFICULTY: u32 = 32;
+
+// number of leading zero bits of bytes
+pub fn leading_zeros(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    zeros
+}
+
+pub fn solves_static(id: &Key, difficulty: u32) -> bool {
+    difficulty == 0 || leading_zeros(&Sha256::digest(&id.0)) >= difficulty
+}
+
+pub fn solves_dynamic(id: &Key, x: &Key, difficulty: u32) -> bool {
+    difficulty == 0 || leading_zeros(&Sha256::digest(&Distance::new(id, x).0)) >= difficulty
+}
+
+// finds an x solving the dynamic puzzle for id, about 2^difficulty attempts
+pub fn solve_dynamic(id: &Key, difficulty: u32) -> Key {
+    loop {
+        let x = Key::random();
+        if solves_dynamic(id, &x, difficulty) {
+            return x;
+        }
+    }
+}
+
+// the id of a sender is accepted only when it solves both puzzles
+pub fn verify(id: &Key, x: &Key, static_difficulty: u32, dynamic_difficulty: u32) -> bool {
+    solves_static(id, static_difficulty) && solves_dynamic(id, x, dynamic_difficulty)
+}
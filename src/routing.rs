@@ -1,34 +1,64 @@
 use super::key::{Distance, Key};
-use super::network;
 use super::node::Node;
-use super::utils::ChannelPayload;
+use super::puzzle;
 use super::K_PARAM;
 use super::N_BUCKETS;
 
-use crossbeam_channel;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Debug, Serialize, Deserialize, Eq, Hash, Clone)]
+#[derive(Debug, Serialize, Deserialize, Eq, Clone)]
 pub struct NodeAndDistance(pub Node, pub Distance);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum FindValueResult {
     Nodes(Vec<NodeAndDistance>),
-    Value(String),
+    // value and its remaining time to live
+    Value(#[serde(with = "serde_bytes")] Vec<u8>, std::time::Duration),
 }
 
 #[derive(Debug)]
 pub struct KBucket {
     pub nodes: Vec<Node>,
     pub size: usize,
+    // contacts seen while the bucket was full, most recent last
+    pub replacements: Vec<Node>,
+    // least-recently seen contact whose liveness check is in flight
+    pub probing: Option<Node>,
+    // last time a contact was added or refreshed, or a lookup targeted this bucket
+    pub last_touched: Instant,
 }
 
 #[derive(Debug)]
 pub struct RoutingTable {
     pub node: Node,
     pub kbuckets: Vec<KBucket>,
-    pub sender: crossbeam_channel::Sender<ChannelPayload>,
-    pub receiver: crossbeam_channel::Receiver<ChannelPayload>,
+    // last time we heard from each contact, replacements included
+    pub last_seen: HashMap<Key, SystemTime>,
+    // contacts whose id doesn't solve the static puzzle are refused, see puzzle.rs
+    pub static_difficulty: u32,
+    pub ip_limits: IpLimits,
+}
+
+// Caps on the contacts sharing an IP or a subnet (/24 for IPv4, /64 for IPv6), in every
+// bucket and in the whole table, so that a single host can't fill the routing table by
+// running many nodes. Contacts over a cap are refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpLimits {
+    pub bucket_ip: usize,
+    pub bucket_subnet: usize,
+    pub table_ip: usize,
+    pub table_subnet: usize,
+}
+
+// a contact saved to disk, used to warm up the routing table of a restarted node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedContact {
+    pub node: Node,
+    pub last_seen: SystemTime,
 }
 
 impl PartialEq for NodeAndDistance {
@@ -47,9 +77,16 @@ impl PartialEq for NodeAndDistance {
     }
 }
 
+// equality only looks at the distance, so hashing must do the same
+impl std::hash::Hash for NodeAndDistance {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.1.hash(state);
+    }
+}
+
 impl PartialOrd for NodeAndDistance {
     fn partial_cmp(&self, other: &NodeAndDistance) -> Option<std::cmp::Ordering> {
-        Some(other.1.cmp(&self.1))
+        Some(self.cmp(other))
     }
 }
 
@@ -63,42 +100,117 @@ impl Ord for NodeAndDistance {
 // have a distance between 2^i and 2^i+1 to the own id
 impl KBucket {
     pub fn new() -> Self {
+        Self::with_size(K_PARAM)
+    }
+
+    pub fn with_size(size: usize) -> Self {
         Self {
             nodes: Vec::new(),
-            size: K_PARAM,
+            size,
+            replacements: Vec::new(),
+            probing: None,
+            last_touched: Instant::now(),
+        }
+    }
+
+    // the replacement cache is as large as the bucket, the oldest entry is dropped first
+    // and returned
+    fn add_replacement(&mut self, node: Node) -> Option<Node> {
+        if let Some(i) = self.replacements.iter().position(|x| x.id == node.id) {
+            self.replacements.remove(i);
+        }
+        let dropped = if self.replacements.len() >= self.size {
+            Some(self.replacements.remove(0))
+        } else {
+            None
+        };
+
+        self.replacements.push(node);
+        dropped
+    }
+}
+
+impl Default for KBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Default for IpLimits {
+    fn default() -> Self {
+        Self {
+            bucket_ip: 1,
+            bucket_subnet: 2,
+            table_ip: 5,
+            table_subnet: 10,
+        }
+    }
+}
+
+impl IpLimits {
+    // test mode: no caps, for local setups running every node on the same host
+    pub fn disabled() -> Self {
+        Self {
+            bucket_ip: usize::MAX,
+            bucket_subnet: usize::MAX,
+            table_ip: usize::MAX,
+            table_subnet: usize::MAX,
         }
     }
 }
 
+// the /24 (IPv4) or /64 (IPv6) network of ip, anything else is a network of its own
+pub fn subnet(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let octets = ip.octets();
+            format!("{}.{}.{}.0/24", octets[0], octets[1], octets[2])
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+        Err(_) => ip.to_owned(),
+    }
+}
+
 impl RoutingTable {
-    pub fn new(
+    // contacts are only added once they answer, see Protocol::join
+    pub fn new(node: Node, bucket_size: usize) -> Self {
+        Self::with_difficulty(node, bucket_size, 0)
+    }
+
+    pub fn with_difficulty(node: Node, bucket_size: usize, static_difficulty: u32) -> Self {
+        Self::with_limits(node, bucket_size, static_difficulty, IpLimits::disabled())
+    }
+
+    pub fn with_limits(
         node: Node,
-        bootstrap: Option<Node>,
-        sender: crossbeam_channel::Sender<ChannelPayload>,
-        receiver: crossbeam_channel::Receiver<ChannelPayload>,
+        bucket_size: usize,
+        static_difficulty: u32,
+        ip_limits: IpLimits,
     ) -> Self {
         let mut kbuckets: Vec<KBucket> = Vec::new();
         for _ in 0..N_BUCKETS {
-            kbuckets.push(KBucket::new());
+            kbuckets.push(KBucket::with_size(bucket_size));
         }
 
         let mut ret = Self {
             node: node.clone(),
             kbuckets,
-            sender,
-            receiver,
+            last_seen: HashMap::new(),
+            static_difficulty,
+            ip_limits,
         };
 
-        ret.update(node);
-
-        if let Some(bootstrap) = bootstrap {
-            ret.update(bootstrap);
-        }
-
+        ret.try_update(node);
         ret
     }
 
-    fn get_lookup_bucket_index(&self, key: &Key) -> usize {
+    pub(crate) fn get_lookup_bucket_index(&self, key: &Key) -> usize {
         // https://stackoverflow.com/questions/2656642/easiest-way-to-find-the-correct-kademlia-bucket
 
         // given a bucket j, we are guaranteed that
@@ -120,75 +232,242 @@ impl RoutingTable {
         super::KEY_LEN * 8 - 1
     }
 
-    fn contact_via_rpc(&self, dst: Node) -> bool {
-        if let Err(_) = self
-            .sender
-            .send(ChannelPayload::Request((network::Request::Ping, dst)))
-        {
-            println!(
-                "[FAILED] RoutingTable::contact_via_rpc --> Receiver is dead, closing channel"
-            );
-            return false;
+    // inserts or refreshes a contact without any network activity.
+    // When the bucket is full the contact goes to the replacement cache and the
+    // least-recently seen contact is returned (unless it's already being checked):
+    // it's up to the caller to ping it and report back with ping_result.
+    // Contacts whose id doesn't solve the static puzzle are ignored, and so are new ones
+    // over the IP limits (unless they're already in the replacement cache) and known ones
+    // moving to an IP over them
+    pub fn try_update(&mut self, node: Node) -> Option<Node> {
+        if !puzzle::solves_static(&node.id, self.static_difficulty) {
+            return None;
         }
 
-        true
-    }
-
-    pub fn update(&mut self, node: Node) {
         let bucket_idx = self.get_lookup_bucket_index(&node.id);
+        let known_at_ip = self.kbuckets[bucket_idx]
+            .nodes
+            .iter()
+            .chain(&self.kbuckets[bucket_idx].replacements)
+            .any(|x| x.id == node.id && x.ip == node.ip);
+        if !known_at_ip && !self.within_ip_limits(&node, bucket_idx) {
+            return None;
+        }
 
-        // TODO(testing): fill buckets with dummy nodes so we can reach the else statement
-        if self.kbuckets[bucket_idx].nodes.len() < K_PARAM {
-            let node_idx = self.kbuckets[bucket_idx]
-                .nodes
-                .iter()
-                .position(|x| x.id == node.id);
-            match node_idx {
-                Some(i) => {
-                    self.kbuckets[bucket_idx].nodes.remove(i);
-                    self.kbuckets[bucket_idx].nodes.push(node);
-                }
-                None => {
-                    self.kbuckets[bucket_idx].nodes.push(node);
-                }
+        let bucket = &mut self.kbuckets[bucket_idx];
+        bucket.last_touched = Instant::now();
+        self.last_seen.insert(node.id.clone(), SystemTime::now());
+
+        match bucket.nodes.iter().position(|x| x.id == node.id) {
+            Some(i) => {
+                bucket.nodes.remove(i);
+                bucket.nodes.push(node);
+                None
             }
-        } else {
-            let _success = self.contact_via_rpc(self.kbuckets[bucket_idx].nodes[0].clone());
-            let receiver = self.receiver.clone();
-
-            // We must make it blocking (no threads) because we still want to use the mutable reference to the buckets
-            // we don't want to clone and move
-            let res = receiver
-                .recv()
-                .expect("[FAILED] Routing::update --> Failed  to receive data from channel");
-            match res {
-                ChannelPayload::Response(_) => {
-                    let to_re_add = self.kbuckets[bucket_idx].nodes.remove(0);
-                    self.kbuckets[bucket_idx].nodes.push(to_re_add);
-                }
-                ChannelPayload::Request(_) => {
-                    eprintln!("[FAILED] Routing::update --> Unexpectedly got a Request instead of a Response");
+            None if bucket.nodes.len() < bucket.size => {
+                bucket.nodes.push(node);
+                None
+            }
+            None => {
+                if let Some(dropped) = bucket.add_replacement(node) {
+                    self.last_seen.remove(&dropped.id);
                 }
-                ChannelPayload::NoData => {
-                    self.kbuckets[bucket_idx].nodes.remove(0);
-                    self.kbuckets[bucket_idx].nodes.push(node);
+                if bucket.probing.is_some() {
+                    return None;
                 }
-            };
+
+                bucket.probing = Some(bucket.nodes[0].clone());
+                bucket.probing.clone()
+            }
         }
     }
 
-    pub fn remove(&mut self, node: &Node) {
-        let bucket_idx = self.get_lookup_bucket_index(&node.id);
+    // outcome of the liveness check of a contact returned by try_update:
+    // an alive contact is refreshed, a dead one is replaced from the replacement cache
+    pub fn ping_result(&mut self, lrs: &Node, alive: bool) {
+        let bucket_idx = self.get_lookup_bucket_index(&lrs.id);
+        let bucket = &mut self.kbuckets[bucket_idx];
+
+        if bucket.probing.as_ref().map(|x| &x.id) == Some(&lrs.id) {
+            bucket.probing = None;
+        }
+
+        if let Some(i) = bucket.nodes.iter().position(|x| x.id == lrs.id) {
+            let lrs = bucket.nodes.remove(i);
+            if alive {
+                bucket.nodes.push(lrs);
+            } else {
+                self.last_seen.remove(&lrs.id);
+                self.promote(bucket_idx);
+            }
+        }
+    }
+
+    // marks the bucket of key as recently used, called when a lookup starts
+    pub fn touch(&mut self, key: &Key) {
+        let bucket_idx = self.get_lookup_bucket_index(key);
+        self.kbuckets[bucket_idx].last_touched = Instant::now();
+    }
+
+    // indexes of the buckets with contacts (other than us) that nobody touched for max_idle
+    pub fn stale_buckets(&self, max_idle: Duration) -> Vec<usize> {
+        self.kbuckets
+            .iter()
+            .enumerate()
+            .filter(|(_, kb)| kb.last_touched.elapsed() >= max_idle)
+            .filter(|(_, kb)| kb.nodes.iter().any(|x| x.id != self.node.id))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // a random id falling into the bucket with the given index.
+    // get_lookup_bucket_index maps bucket i*8 + j to the distances whose first
+    // non-zero byte is the i-th one and has its lowest set bit in position 7 - j
+    pub fn random_id_in_bucket(&self, bucket_idx: usize) -> Key {
+        let mut rng = rand::thread_rng();
+        let (i, j) = (bucket_idx / 8, bucket_idx % 8);
+        let low = 7 - j as u32;
+
+        let mut distance = [0u8; super::KEY_LEN];
+        rng.fill(&mut distance[i + 1..]);
+        distance[i] = (rng.gen::<u8>() & 0xFFu8.checked_shl(low + 1).unwrap_or(0)) | (1 << low);
+
+        let mut id = self.node.id.clone();
+        for (b, d) in id.0.iter_mut().zip(distance.iter()) {
+            *b ^= d;
+        }
+
+        id
+    }
+
+    // number of contacts closer than us to key
+    pub fn count_closer(&self, key: &Key) -> usize {
+        let own = Distance::new(&self.node.id, key);
+
+        self.kbuckets
+            .iter()
+            .flat_map(|kb| kb.nodes.iter())
+            .filter(|x| Distance::new(&x.id, key) < own)
+            .count()
+    }
 
-        if let Some(i) = self.kbuckets[bucket_idx]
+    pub fn contains(&self, id: &Key) -> bool {
+        let bucket_idx = self.get_lookup_bucket_index(id);
+        self.kbuckets[bucket_idx].nodes.iter().any(|x| &x.id == id)
+    }
+
+    // the contact with this id, replacements included
+    pub fn get(&self, id: &Key) -> Option<&Node> {
+        let bucket = &self.kbuckets[self.get_lookup_bucket_index(id)];
+        bucket
             .nodes
             .iter()
-            .position(|x| x.id == node.id)
-        {
-            self.kbuckets[bucket_idx].nodes.remove(i);
+            .chain(&bucket.replacements)
+            .find(|x| &x.id == id)
+    }
+
+    pub fn remove(&mut self, node: &Node) {
+        let bucket_idx = self.get_lookup_bucket_index(&node.id);
+
+        let bucket = &mut self.kbuckets[bucket_idx];
+
+        if let Some(i) = bucket.nodes.iter().position(|x| x.id == node.id) {
+            bucket.nodes.remove(i);
+            self.promote(bucket_idx);
+        } else if let Some(i) = bucket.replacements.iter().position(|x| x.id == node.id) {
+            bucket.replacements.remove(i);
         } else {
             eprintln!("[WARN] Routing::remove --> Tried to remove non-existing entry");
         }
+        self.last_seen.remove(&node.id);
+    }
+
+    // contacts in the buckets (us excluded), to be saved and passed to seed on the next start
+    pub fn contacts(&self) -> Vec<SavedContact> {
+        self.kbuckets
+            .iter()
+            .flat_map(|kb| kb.nodes.iter())
+            .filter(|x| x.id != self.node.id)
+            .map(|x| SavedContact {
+                node: x.clone(),
+                last_seen: self
+                    .last_seen
+                    .get(&x.id)
+                    .copied()
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            })
+            .collect()
+    }
+
+    // inserts a contact saved by a previous run, keeping its last_seen time. Nothing is
+    // probed: the contact is dropped if its bucket is full, returns whether it was added
+    pub fn seed(&mut self, contact: SavedContact) -> bool {
+        let bucket_idx = self.get_lookup_bucket_index(&contact.node.id);
+        let bucket = &self.kbuckets[bucket_idx];
+
+        if contact.node.id == self.node.id
+            || !puzzle::solves_static(&contact.node.id, self.static_difficulty)
+            || bucket.nodes.len() >= bucket.size
+            || bucket.nodes.iter().any(|x| x.id == contact.node.id)
+            || !self.within_ip_limits(&contact.node, bucket_idx)
+        {
+            return false;
+        }
+
+        self.kbuckets[bucket_idx].nodes.push(contact.node.clone());
+        self.last_seen.insert(contact.node.id, contact.last_seen);
+        true
+    }
+
+    // whether a contact, new or moving, stays within the IP limits of the given bucket,
+    // we don't count against them
+    fn within_ip_limits(&self, node: &Node, bucket_idx: usize) -> bool {
+        if node.id == self.node.id {
+            return true;
+        }
+
+        let net = subnet(&node.ip);
+        let count = |nodes: &mut dyn Iterator<Item = &Node>| {
+            let (mut ip, mut subnet_count) = (0, 0);
+            // the entry node replaces when it moves doesn't count either
+            for x in nodes.filter(|x| x.id != self.node.id && x.id != node.id) {
+                if x.ip == node.ip {
+                    ip += 1;
+                }
+                if subnet(&x.ip) == net {
+                    subnet_count += 1;
+                }
+            }
+            (ip, subnet_count)
+        };
+
+        let bucket = &self.kbuckets[bucket_idx];
+        let (bucket_ip, bucket_subnet) =
+            count(&mut bucket.nodes.iter().chain(&bucket.replacements));
+        let (table_ip, table_subnet) =
+            count(&mut self.kbuckets.iter().flat_map(|kb| kb.nodes.iter()));
+
+        bucket_ip < self.ip_limits.bucket_ip
+            && bucket_subnet < self.ip_limits.bucket_subnet
+            && table_ip < self.ip_limits.table_ip
+            && table_subnet < self.ip_limits.table_subnet
+    }
+
+    // fills a free slot of the bucket with the most recently seen replacement that,
+    // once the other contacts changed, still fits the IP limits
+    fn promote(&mut self, bucket_idx: usize) {
+        let bucket = &self.kbuckets[bucket_idx];
+        if bucket.nodes.len() >= bucket.size {
+            return;
+        }
+
+        while let Some(node) = self.kbuckets[bucket_idx].replacements.pop() {
+            if self.within_ip_limits(&node, bucket_idx) {
+                self.kbuckets[bucket_idx].nodes.push(node);
+                return;
+            }
+            self.last_seen.remove(&node.id);
+        }
     }
 
     pub fn get_closest_nodes(&self, key: &Key, count: usize) -> Vec<NodeAndDistance> {
@@ -239,7 +518,7 @@ impl RoutingTable {
             }
         }
 
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
+        ret.sort_by_key(|a| a.1);
         ret.truncate(count);
         ret
     }
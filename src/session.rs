@@ -0,0 +1,670 @@
+use super::identity::{self, Identity, PUBLIC_KEY_LEN};
+use super::key::Key;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::oneshot;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::{Debug, Display, Error, Formatter};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+    Encrypted sessions between two nodes, set up with a handshake in the spirit of Noise XX:
+
+        initiator --> [HANDSHAKE_MAGIC] Init   { session, ephemeral_i, public_key_i, signature_i }
+        responder --> [HANDSHAKE_MAGIC] Accept { session, ephemeral_r, public_key_r, signature_r }
+
+    signature_i covers the session id, ephemeral_i and the responder address, signature_r covers
+    the session id and both ephemeral keys: each side proves it holds the Ed25519 identity
+    whose id it claims. The x25519 shared secret of the ephemeral keys goes through HKDF-SHA256
+    to get one ChaCha20Poly1305 key per direction. Messages then travel as
+
+        [SEALED_MAGIC][session: u64][nonce: 12 bytes][ciphertext...]
+
+    where magic and session id are authenticated as associated data. The nonce holds a counter
+    of the messages sealed in that direction: a sealed message is accepted once, only from the
+    address of the session peer, and at most REPLAY_WINDOW messages late. Whole messages
+    (see codec.rs) and fragments (see fragment.rs) never start with either magic byte.
+*/
+
+const HANDSHAKE_MAGIC: u8 = 0xE1;
+const SEALED_MAGIC: u8 = 0xE5;
+
+const SEALED_HEADER_LEN: usize = 1 + 8;
+const NONCE_LEN: usize = 12;
+
+// sessions kept at the same time, the oldest one is dropped to make room
+pub const MAX_SESSIONS: usize = 1024;
+
+// Inits are counted per source IP over INIT_PERIOD, each costs a signature check,
+// a signature and a key exchange
+pub const INIT_PERIOD: Duration = Duration::from_secs(1);
+
+// how far behind the latest message received in a session an older one may arrive,
+// the bits of ReplayWindow::seen
+pub const REPLAY_WINDOW: u64 = 128;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionError {
+    Malformed,
+    // the handshake isn't signed by the key it carries, or is meant for someone else
+    BadSignature,
+    // sealed with a session we don't have, e.g. we restarted since the handshake
+    UnknownSession,
+    // an Accept nobody was waiting for
+    UnexpectedAccept,
+    // the ciphertext was altered
+    Decrypt,
+    // the decrypted message claims a different sender than the one of the session
+    WrongPeer,
+    // the Accept is signed by another node than the one we meant to reach
+    UnexpectedPeer,
+    // sealed with the session of another address
+    WrongAddress,
+    // a message already received, or too old to tell
+    Replayed,
+    // an Init for a session id we already have, e.g. replayed
+    DuplicateSession,
+    // too many Inits from the same IP, see Sessions::new
+    RateLimited,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Handshake {
+    Init {
+        session: u64,
+        ephemeral: [u8; 32],
+        public_key: [u8; PUBLIC_KEY_LEN],
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+    },
+    Accept {
+        session: u64,
+        ephemeral: [u8; 32],
+        public_key: [u8; PUBLIC_KEY_LEN],
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+    },
+}
+
+// an established session with peer, whose identity is peer_id
+pub struct Session {
+    pub id: u64,
+    pub peer: String,
+    pub peer_id: Key,
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    created: Instant,
+    // counter of the next message we seal
+    sent: AtomicU64,
+    received: Mutex<ReplayWindow>,
+}
+
+// counters of the messages received lately in a session: bit i of seen is next - 1 - i
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    next: u64,
+    seen: u128,
+}
+
+// handshake we started and are waiting an Accept for,
+// everyone sending to peer meanwhile waits for the same one
+struct Pending {
+    peer: String,
+    // the id we expect to answer, None when peer is only known by address
+    expected: Option<Key>,
+    secret: StaticSecret,
+    waiting: Vec<oneshot::Sender<Arc<Session>>>,
+}
+
+// The sessions of a node: the one used to send to each peer,
+// and every one we may receive from by session id
+pub struct Sessions {
+    identity: Arc<Identity>,
+    addr: String,
+    by_peer: Mutex<HashMap<String, Arc<Session>>>,
+    by_id: Mutex<HashMap<u64, Arc<Session>>>,
+    handshakes: Mutex<HashMap<u64, Pending>>,
+    // Inits handled lately per source IP: start of the period and count
+    inits: Mutex<HashMap<String, (Instant, usize)>>,
+    max_inits: usize,
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            SessionError::Malformed => write!(f, "malformed handshake or sealed message"),
+            SessionError::BadSignature => write!(f, "handshake with a bad signature"),
+            SessionError::UnknownSession => write!(f, "unknown session"),
+            SessionError::UnexpectedAccept => write!(f, "unexpected handshake response"),
+            SessionError::Decrypt => write!(f, "unable to decrypt message"),
+            SessionError::WrongPeer => write!(f, "message from someone else than the session peer"),
+            SessionError::UnexpectedPeer => {
+                write!(f, "handshake accepted by another node than expected")
+            }
+            SessionError::WrongAddress => {
+                write!(f, "message from another address than the session peer")
+            }
+            SessionError::Replayed => write!(f, "replayed message"),
+            SessionError::DuplicateSession => write!(f, "handshake for an existing session"),
+            SessionError::RateLimited => write!(f, "too many handshakes from the same address"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl Debug for Session {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "Session({:016X}, {})", self.id, self.peer)
+    }
+}
+
+impl Debug for Sessions {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let sessions = self
+            .by_id
+            .lock()
+            .expect("[FAILED] Sessions::fmt --> Failed to acquire mutex on Sessions")
+            .len();
+        write!(f, "Sessions({}, {} sessions)", self.addr, sessions)
+    }
+}
+
+pub fn is_handshake(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&HANDSHAKE_MAGIC)
+}
+
+pub fn is_sealed(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&SEALED_MAGIC)
+}
+
+fn init_bytes(session: u64, ephemeral: &[u8; 32], responder: &str) -> Vec<u8> {
+    let mut bytes = b"kademlia-dht init".to_vec();
+    bytes.extend_from_slice(&session.to_le_bytes());
+    bytes.extend_from_slice(ephemeral);
+    bytes.extend_from_slice(responder.as_bytes());
+    bytes
+}
+
+fn accept_bytes(session: u64, initiator: &[u8; 32], responder: &[u8; 32]) -> Vec<u8> {
+    let mut bytes = b"kademlia-dht accept".to_vec();
+    bytes.extend_from_slice(&session.to_le_bytes());
+    bytes.extend_from_slice(initiator);
+    bytes.extend_from_slice(responder);
+    bytes
+}
+
+fn encode(handshake: &Handshake) -> Vec<u8> {
+    let mut buf = vec![HANDSHAKE_MAGIC];
+    bincode::serialize_into(&mut buf, handshake)
+        .expect("[FAILED] Sessions::encode --> Failed to serialize handshake");
+    buf
+}
+
+impl ReplayWindow {
+    // whether counter wasn't seen yet, it's then marked as seen
+    fn accept(&mut self, counter: u64) -> bool {
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            let kept = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen = kept | 1;
+            self.next = counter + 1;
+            return true;
+        }
+
+        let age = self.next - 1 - counter;
+        if age >= REPLAY_WINDOW {
+            return false;
+        }
+        let bit = 1u128 << age;
+        let fresh = self.seen & bit == 0;
+        self.seen |= bit;
+        fresh
+    }
+}
+
+impl Session {
+    // both ends derive the same keys, the initiator sends with the first one
+    fn new(
+        id: u64,
+        peer: String,
+        peer_id: Key,
+        shared: &[u8; 32],
+        initiator: &[u8; 32],
+        responder: &[u8; 32],
+        is_initiator: bool,
+    ) -> Self {
+        let mut info = b"kademlia-dht session".to_vec();
+        info.extend_from_slice(&id.to_le_bytes());
+        info.extend_from_slice(initiator);
+        info.extend_from_slice(responder);
+
+        let mut okm = [0u8; 64];
+        Hkdf::<Sha256>::new(None, shared)
+            .expand(&info, &mut okm)
+            .expect("[FAILED] Session::new --> Failed to expand session keys");
+        let i2r = ChaCha20Poly1305::new_from_slice(&okm[..32])
+            .expect("[FAILED] Session::new --> Invalid key length");
+        let r2i = ChaCha20Poly1305::new_from_slice(&okm[32..])
+            .expect("[FAILED] Session::new --> Invalid key length");
+
+        let (send, recv) = if is_initiator { (i2r, r2i) } else { (r2i, i2r) };
+        Self {
+            id,
+            peer,
+            peer_id,
+            send,
+            recv,
+            created: Instant::now(),
+            sent: AtomicU64::new(0),
+            received: Mutex::new(ReplayWindow::default()),
+        }
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut sealed = vec![SEALED_MAGIC];
+        sealed.extend_from_slice(&self.id.to_le_bytes());
+
+        // every direction has its own key, so a counter never repeats a nonce
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.sent.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        let ciphertext = self
+            .send
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &sealed,
+                },
+            )
+            .expect("[FAILED] Session::seal --> Failed to encrypt message");
+
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, SessionError> {
+        let (header, rest) = sealed.split_at(SEALED_HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let plaintext = self
+            .recv
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| SessionError::Decrypt)?;
+
+        // only authentic counters move the window
+        let counter =
+            u64::from_le_bytes(nonce[..8].try_into().map_err(|_| SessionError::Malformed)?);
+        if !self
+            .received
+            .lock()
+            .expect("[FAILED] Session::open --> Failed to acquire mutex on Received")
+            .accept(counter)
+        {
+            return Err(SessionError::Replayed);
+        }
+
+        Ok(plaintext)
+    }
+}
+
+impl Sessions {
+    // addr is the address of the node, handshakes meant for other addresses are refused.
+    // At most max_inits handshakes started by the same IP are handled every INIT_PERIOD
+    pub fn new(identity: Arc<Identity>, addr: String, max_inits: usize) -> Self {
+        Self {
+            identity,
+            addr,
+            by_peer: Mutex::new(HashMap::new()),
+            by_id: Mutex::new(HashMap::new()),
+            handshakes: Mutex::new(HashMap::new()),
+            inits: Mutex::new(HashMap::new()),
+            max_inits,
+        }
+    }
+
+    // the session used to send to peer, if any
+    pub fn get(&self, peer: &str) -> Option<Arc<Session>> {
+        self.by_peer
+            .lock()
+            .expect("[FAILED] Sessions::get --> Failed to acquire mutex on Sessions")
+            .get(peer)
+            .cloned()
+    }
+
+    // the next message to peer needs a new handshake, e.g. it restarted and lost the session
+    pub fn forget(&self, peer: &str) {
+        let session = self
+            .by_peer
+            .lock()
+            .expect("[FAILED] Sessions::forget --> Failed to acquire mutex on Sessions")
+            .remove(peer);
+
+        if let Some(session) = session {
+            self.by_id
+                .lock()
+                .expect("[FAILED] Sessions::forget --> Failed to acquire mutex on Sessions")
+                .remove(&session.id);
+        }
+    }
+
+    // returns the session id, the Init to send to peer and where the session shows up
+    // once peer accepts as expected, see handle_handshake. There's no Init to send when
+    // a handshake with peer is already going on, and it may expect another id
+    pub fn initiate(
+        &self,
+        peer: &str,
+        expected: Option<&Key>,
+    ) -> (u64, Option<Vec<u8>>, oneshot::Receiver<Arc<Session>>) {
+        let (sender, receiver) = oneshot::channel();
+
+        let mut handshakes = self
+            .handshakes
+            .lock()
+            .expect("[FAILED] Sessions::initiate --> Failed to acquire mutex on Handshakes");
+        if let Some((id, pending)) = handshakes.iter_mut().find(|(_, p)| p.peer == peer) {
+            pending.waiting.push(sender);
+            return (*id, None, receiver);
+        }
+
+        let session: u64 = rand::random();
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let ephemeral = PublicKey::from(&secret).to_bytes();
+
+        let init = encode(&Handshake::Init {
+            session,
+            ephemeral,
+            public_key: self.identity.public_key(),
+            signature: self.identity.sign(&init_bytes(session, &ephemeral, peer)),
+        });
+
+        handshakes.insert(
+            session,
+            Pending {
+                peer: peer.to_string(),
+                expected: expected.cloned(),
+                secret,
+                waiting: vec![sender],
+            },
+        );
+
+        (session, Some(init), receiver)
+    }
+
+    // the handshake didn't complete in time
+    pub fn abandon(&self, session: u64) {
+        self.handshakes
+            .lock()
+            .expect("[FAILED] Sessions::abandon --> Failed to acquire mutex on Handshakes")
+            .remove(&session);
+    }
+
+    // handles a handshake datagram from src, returns the Accept to send back for an Init
+    pub fn handle_handshake(
+        &self,
+        src: &str,
+        datagram: &[u8],
+    ) -> Result<Option<Vec<u8>>, SessionError> {
+        if !is_handshake(datagram) {
+            return Err(SessionError::Malformed);
+        }
+        let handshake: Handshake =
+            bincode::deserialize(&datagram[1..]).map_err(|_| SessionError::Malformed)?;
+
+        match handshake {
+            Handshake::Init {
+                session,
+                ephemeral,
+                public_key,
+                signature,
+            } => {
+                // cheap checks first, the rest is expensive
+                if self
+                    .by_id
+                    .lock()
+                    .expect("[FAILED] Sessions::handle_handshake --> Failed to acquire mutex on Sessions")
+                    .contains_key(&session)
+                {
+                    return Err(SessionError::DuplicateSession);
+                }
+                self.count_init(src)?;
+
+                let peer_id = identity::id_of(&public_key);
+                let signed = init_bytes(session, &ephemeral, &self.addr);
+                if !identity::verify(&peer_id, &public_key, &signed, &signature) {
+                    return Err(SessionError::BadSignature);
+                }
+
+                let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+                let own_ephemeral = PublicKey::from(&secret).to_bytes();
+                let shared = secret.diffie_hellman(&PublicKey::from(ephemeral));
+                if !shared.was_contributory() {
+                    return Err(SessionError::Malformed);
+                }
+
+                let accept = encode(&Handshake::Accept {
+                    session,
+                    ephemeral: own_ephemeral,
+                    public_key: self.identity.public_key(),
+                    signature: self.identity.sign(&accept_bytes(
+                        session,
+                        &ephemeral,
+                        &own_ephemeral,
+                    )),
+                });
+                // anyone can send an Init from src, it doesn't replace the session
+                // we have with src until it's used, see open
+                self.insert(
+                    Session::new(
+                        session,
+                        src.to_string(),
+                        peer_id,
+                        shared.as_bytes(),
+                        &ephemeral,
+                        &own_ephemeral,
+                        false,
+                    ),
+                    false,
+                );
+
+                Ok(Some(accept))
+            }
+            Handshake::Accept {
+                session,
+                ephemeral,
+                public_key,
+                signature,
+            } => {
+                // a bad Accept leaves the handshake waiting for the real one
+                let (own_ephemeral, expected) = match self
+                    .handshakes
+                    .lock()
+                    .expect("[FAILED] Sessions::handle_handshake --> Failed to acquire mutex on Handshakes")
+                    .get(&session)
+                {
+                    Some(pending) if pending.peer == src => (
+                        PublicKey::from(&pending.secret).to_bytes(),
+                        pending.expected.clone(),
+                    ),
+                    _ => return Err(SessionError::UnexpectedAccept),
+                };
+                let peer_id = identity::id_of(&public_key);
+                let signed = accept_bytes(session, &own_ephemeral, &ephemeral);
+                if !identity::verify(&peer_id, &public_key, &signed, &signature) {
+                    return Err(SessionError::BadSignature);
+                }
+                if expected.is_some_and(|id| id != peer_id) {
+                    return Err(SessionError::UnexpectedPeer);
+                }
+
+                // someone else may have completed it meanwhile
+                let pending = self
+                    .handshakes
+                    .lock()
+                    .expect("[FAILED] Sessions::handle_handshake --> Failed to acquire mutex on Handshakes")
+                    .remove(&session)
+                    .ok_or(SessionError::UnexpectedAccept)?;
+
+                let shared = pending.secret.diffie_hellman(&PublicKey::from(ephemeral));
+                if !shared.was_contributory() {
+                    return Err(SessionError::Malformed);
+                }
+
+                let session = self.insert(
+                    Session::new(
+                        session,
+                        pending.peer,
+                        peer_id,
+                        shared.as_bytes(),
+                        &own_ephemeral,
+                        &ephemeral,
+                        true,
+                    ),
+                    true,
+                );
+                // senders may have given up in the meantime
+                for sender in pending.waiting {
+                    let _ = sender.send(session.clone());
+                }
+
+                Ok(None)
+            }
+        }
+    }
+
+    // decrypts a sealed datagram from src, returns the session it belongs to and the plaintext
+    pub fn open(&self, src: &str, sealed: &[u8]) -> Result<(Arc<Session>, Vec<u8>), SessionError> {
+        if !is_sealed(sealed) || sealed.len() < SEALED_HEADER_LEN + NONCE_LEN {
+            return Err(SessionError::Malformed);
+        }
+
+        let id = u64::from_le_bytes(
+            sealed[1..SEALED_HEADER_LEN]
+                .try_into()
+                .map_err(|_| SessionError::Malformed)?,
+        );
+        let session = self
+            .by_id
+            .lock()
+            .expect("[FAILED] Sessions::open --> Failed to acquire mutex on Sessions")
+            .get(&id)
+            .cloned()
+            .ok_or(SessionError::UnknownSession)?;
+        // the session keys alone don't bind the address, whoever replays a datagram has them
+        if session.peer != src {
+            return Err(SessionError::WrongAddress);
+        }
+
+        let plaintext = session.open(sealed)?;
+        self.promote(&session);
+        Ok((session, plaintext))
+    }
+
+    // counts an Init from src, refused past max_inits in the current period
+    fn count_init(&self, src: &str) -> Result<(), SessionError> {
+        // ports are free, the IP is what a sender can't change as easily
+        let ip = src
+            .parse::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| src.to_string());
+        let now = Instant::now();
+
+        let mut inits = self
+            .inits
+            .lock()
+            .expect("[FAILED] Sessions::count_init --> Failed to acquire mutex on Inits");
+        if inits.len() >= MAX_SESSIONS {
+            inits.retain(|_, (start, _)| now.duration_since(*start) < INIT_PERIOD);
+        }
+
+        let (start, count) = inits.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= INIT_PERIOD {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.max_inits {
+            return Err(SessionError::RateLimited);
+        }
+        *count += 1;
+
+        Ok(())
+    }
+
+    // a session accepted from an Init becomes the one used to send to its peer once
+    // it opened a message: whoever sent it completed the handshake from that address
+    fn promote(&self, session: &Arc<Session>) {
+        let mut by_peer = self
+            .by_peer
+            .lock()
+            .expect("[FAILED] Sessions::promote --> Failed to acquire mutex on Sessions");
+        let newer = match by_peer.get(&session.peer) {
+            Some(current) => current.id != session.id && current.created < session.created,
+            None => true,
+        };
+        // it may have been evicted meanwhile
+        if newer
+            && self
+                .by_id
+                .lock()
+                .expect("[FAILED] Sessions::promote --> Failed to acquire mutex on Sessions")
+                .contains_key(&session.id)
+        {
+            by_peer.insert(session.peer.clone(), session.clone());
+        }
+    }
+
+    // the session becomes the one used to send to its peer when replace is set,
+    // or when there's none yet
+    fn insert(&self, session: Session, replace: bool) -> Arc<Session> {
+        let session = Arc::new(session);
+
+        // both maps change together, so that by_peer never outgrows by_id
+        let mut by_peer = self
+            .by_peer
+            .lock()
+            .expect("[FAILED] Sessions::insert --> Failed to acquire mutex on Sessions");
+        let mut by_id = self
+            .by_id
+            .lock()
+            .expect("[FAILED] Sessions::insert --> Failed to acquire mutex on Sessions");
+        if by_id.len() >= MAX_SESSIONS {
+            let oldest = by_id
+                .values()
+                .min_by_key(|s| s.created)
+                .cloned()
+                .expect("[FAILED] Sessions::insert --> No sessions to evict");
+            by_id.remove(&oldest.id);
+            // unless a newer session with the same peer replaced it
+            if by_peer.get(&oldest.peer).map(|s| s.id) == Some(oldest.id) {
+                by_peer.remove(&oldest.peer);
+            }
+        }
+        by_id.insert(session.id, session.clone());
+        if replace || !by_peer.contains_key(&session.peer) {
+            by_peer.insert(session.peer.clone(), session.clone());
+        }
+
+        session
+    }
+}
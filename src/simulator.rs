@@ -0,0 +1,538 @@
+use super::error::KademliaError;
+use super::key::{Distance, Key};
+use super::lookup::DisjointLookup;
+use super::node::Node;
+use super::routing::{FindValueResult, NodeAndDistance, RoutingTable};
+use super::ALPHA;
+use super::K_PARAM;
+use super::TIMEOUT;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::time::Duration;
+
+/*
+    Discrete-event simulator for large networks.
+
+    Nodes are plain RoutingTables and stores living in a single Vec, messages are
+    function calls on a virtual network with seeded latencies and losses, time is a
+    virtual clock. Lookups use the same DisjointLookup state machine as Protocol, so changes
+    to the lookup algorithm, to get_closest_nodes or to disjoint_paths can be evaluated
+    here first.
+
+    Every scheduled operation (join, failure, lookup, put, get) runs to completion
+    before the next one starts: the virtual time it takes is the sum of its rounds,
+    where a round lasts as long as its slowest query (or TIMEOUT if a query is lost).
+*/
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub nodes: usize,
+    pub lookups: usize,
+    // every put is followed by a get of the same key from a random node
+    pub puts: usize,
+    pub seed: u64,
+    // one-way latency bounds in milliseconds
+    pub min_latency: u64,
+    pub max_latency: u64,
+    // probability of a single message being dropped
+    pub loss_rate: f64,
+    // fraction of nodes going offline after the network has been built
+    pub failure_rate: f64,
+    pub timeout: u64,
+    // lookups run along this many disjoint paths, see ProtocolConfig::disjoint_paths
+    pub disjoint_paths: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub joins: usize,
+    pub failures: usize,
+    pub lookups: usize,
+    // lookups that found the closest alive node to the target
+    pub successful_lookups: usize,
+    pub lookup_hops: usize,
+    pub lookup_time: u64,
+    pub gets: usize,
+    pub successful_gets: usize,
+    pub get_hops: usize,
+    pub messages: usize,
+    pub lost_messages: usize,
+    // virtual time at the end of the simulation, in milliseconds
+    pub elapsed: u64,
+}
+
+#[derive(Debug)]
+enum Event {
+    Join(usize),
+    Fail(usize),
+    Lookup(Key),
+    Put(String, String),
+    Get(String, String),
+}
+
+#[derive(Debug)]
+struct Scheduled {
+    time: u64,
+    seq: u64,
+    event: Event,
+}
+
+#[derive(Debug)]
+struct SimNode {
+    node: Node,
+    routes: RoutingTable,
+    store: HashMap<String, String>,
+    joined: bool,
+    alive: bool,
+}
+
+#[derive(Debug)]
+pub struct Simulator {
+    pub config: SimConfig,
+    pub stats: SimStats,
+    clock: u64,
+    seq: u64,
+    rng: StdRng,
+    nodes: Vec<SimNode>,
+    ids: HashMap<Key, usize>,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+}
+
+// virtual time between two scheduled operations
+const OP_INTERVAL: u64 = 1000;
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            nodes: 1000,
+            lookups: 100,
+            puts: 100,
+            seed: 0,
+            min_latency: 10,
+            max_latency: 100,
+            loss_rate: 0.0,
+            failure_rate: 0.0,
+            timeout: TIMEOUT,
+            disjoint_paths: 1,
+        }
+    }
+}
+
+impl SimConfig {
+    pub fn validate(&self) -> Result<(), KademliaError> {
+        if self.min_latency > self.max_latency {
+            return Err(KademliaError::InvalidConfig(
+                "min_latency must not be larger than max_latency",
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.loss_rate) || !(0.0..=1.0).contains(&self.failure_rate) {
+            return Err(KademliaError::InvalidConfig(
+                "loss_rate and failure_rate must be between 0 and 1",
+            ));
+        }
+        if self.disjoint_paths == 0 || self.disjoint_paths > K_PARAM {
+            return Err(KademliaError::InvalidConfig(
+                "disjoint_paths must be between 1 and k_param",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl SimStats {
+    pub fn avg_lookup_hops(&self) -> f64 {
+        ratio(self.lookup_hops, self.lookups)
+    }
+
+    pub fn lookup_success_rate(&self) -> f64 {
+        ratio(self.successful_lookups, self.lookups)
+    }
+
+    pub fn avg_get_hops(&self) -> f64 {
+        ratio(self.get_hops, self.gets)
+    }
+
+    pub fn get_success_rate(&self) -> f64 {
+        ratio(self.successful_gets, self.gets)
+    }
+
+    pub fn messages_per_operation(&self) -> f64 {
+        ratio(self.messages, self.joins + self.lookups + self.gets * 2)
+    }
+}
+
+fn ratio(a: usize, b: usize) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Scheduled) -> bool {
+        self.time == other.time && self.seq == other.seq
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Scheduled) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Scheduled) -> Ordering {
+        (self.time, self.seq).cmp(&(other.time, other.seq))
+    }
+}
+
+impl Simulator {
+    // fails with InvalidConfig when the parameters don't make sense
+    pub fn new(config: SimConfig) -> Result<Self, KademliaError> {
+        config.validate()?;
+
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let mut nodes = Vec::with_capacity(config.nodes);
+        let mut ids = HashMap::with_capacity(config.nodes);
+
+        while nodes.len() < config.nodes {
+            let ip = format!(
+                "10.{}.{}.{}",
+                rng.gen::<u8>(),
+                rng.gen::<u8>(),
+                rng.gen::<u8>()
+            );
+            let node = Node::new(ip, rng.gen_range(1024..u16::MAX));
+            if ids.contains_key(&node.id) {
+                continue;
+            }
+
+            let routes = RoutingTable::new(node.clone(), K_PARAM);
+
+            ids.insert(node.id.clone(), nodes.len());
+            nodes.push(SimNode {
+                node,
+                routes,
+                store: HashMap::new(),
+                joined: false,
+                alive: true,
+            });
+        }
+
+        let mut sim = Self {
+            config,
+            stats: SimStats::default(),
+            clock: 0,
+            seq: 0,
+            rng,
+            nodes,
+            ids,
+            queue: BinaryHeap::new(),
+        };
+        sim.schedule_workload();
+
+        Ok(sim)
+    }
+
+    fn schedule(&mut self, time: u64, event: Event) {
+        self.seq += 1;
+        self.queue.push(Reverse(Scheduled {
+            time,
+            seq: self.seq,
+            event,
+        }));
+    }
+
+    fn schedule_workload(&mut self) {
+        let mut time = 0;
+
+        for i in 0..self.config.nodes {
+            self.schedule(time, Event::Join(i));
+            time += OP_INTERVAL;
+        }
+
+        let failures = (self.config.nodes as f64 * self.config.failure_rate) as usize;
+        for _ in 0..failures {
+            let victim = self.rng.gen_range(0..self.config.nodes);
+            self.schedule(time, Event::Fail(victim));
+        }
+
+        for _ in 0..self.config.lookups {
+            let target = Key(self.rng.gen());
+            self.schedule(time, Event::Lookup(target));
+            time += OP_INTERVAL;
+        }
+
+        for i in 0..self.config.puts {
+            let (key, value) = (format!("key_{}", i), format!("value_{}", i));
+            self.schedule(time, Event::Put(key.clone(), value.clone()));
+            self.schedule(time + OP_INTERVAL, Event::Get(key, value));
+            time += OP_INTERVAL * 2;
+        }
+    }
+
+    pub fn run(&mut self) -> SimStats {
+        while let Some(Reverse(scheduled)) = self.queue.pop() {
+            self.clock = std::cmp::max(self.clock, scheduled.time);
+
+            match scheduled.event {
+                Event::Join(idx) => self.join(idx),
+                Event::Fail(idx) => self.fail(idx),
+                Event::Lookup(target) => self.lookup(&target),
+                Event::Put(key, value) => self.put(key, value),
+                Event::Get(key, value) => self.get(&key, &value),
+            }
+        }
+
+        self.stats.elapsed = self.clock;
+        self.stats.clone()
+    }
+
+    fn random_alive_node(&mut self) -> Option<usize> {
+        let alive: Vec<usize> = (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].joined && self.nodes[i].alive)
+            .collect();
+
+        if alive.is_empty() {
+            None
+        } else {
+            Some(alive[self.rng.gen_range(0..alive.len())])
+        }
+    }
+
+    // returns the round trip time of a request, None if a message got lost
+    fn round_trip(&mut self, to: usize) -> Option<u64> {
+        let mut rtt = 0;
+
+        for _ in 0..2 {
+            self.stats.messages += 1;
+
+            if !self.nodes[to].alive || self.rng.gen_bool(self.config.loss_rate) {
+                self.stats.lost_messages += 1;
+                return None;
+            }
+
+            rtt += self
+                .rng
+                .gen_range(self.config.min_latency..=self.config.max_latency);
+        }
+
+        Some(rtt)
+    }
+
+    // what Protocol::add_contact does, with the liveness check going through the virtual network
+    fn add_contact(&mut self, owner: usize, contact: usize) {
+        let node = self.nodes[contact].node.clone();
+
+        if let Some(lrs) = self.nodes[owner].routes.try_update(node) {
+            let lrs_idx = self.ids[&lrs.id];
+            let alive = self.round_trip(lrs_idx).is_some();
+
+            self.nodes[owner].routes.ping_result(&lrs, alive);
+        }
+    }
+
+    fn query_failed(&mut self, from: usize, to: usize) {
+        let node = self.nodes[to].node.clone();
+        if self.nodes[from].routes.contains(&node.id) {
+            self.nodes[from].routes.remove(&node);
+        }
+    }
+
+    fn find_node(
+        &mut self,
+        from: usize,
+        to: usize,
+        target: &Key,
+    ) -> (u64, Option<Vec<NodeAndDistance>>) {
+        match self.round_trip(to) {
+            Some(rtt) => {
+                self.add_contact(to, from);
+                let entries = self.nodes[to].routes.get_closest_nodes(target, K_PARAM);
+                self.add_contact(from, to);
+
+                (rtt, Some(entries))
+            }
+            None => {
+                self.query_failed(from, to);
+                (self.config.timeout, None)
+            }
+        }
+    }
+
+    fn find_value(&mut self, from: usize, to: usize, k: &str) -> (u64, Option<FindValueResult>) {
+        match self.round_trip(to) {
+            Some(rtt) => {
+                self.add_contact(to, from);
+                let res = match self.nodes[to].store.get(k) {
+                    // values never expire in the simulator
+                    Some(v) => FindValueResult::Value(v.as_bytes().to_vec(), Duration::MAX),
+                    None => FindValueResult::Nodes(
+                        self.nodes[to]
+                            .routes
+                            .get_closest_nodes(&Key::new(k.to_string()), K_PARAM),
+                    ),
+                };
+                self.add_contact(from, to);
+
+                (rtt, Some(res))
+            }
+            None => {
+                self.query_failed(from, to);
+                (self.config.timeout, None)
+            }
+        }
+    }
+
+    fn start_lookup(&self, from: usize, target: &Key) -> DisjointLookup {
+        let seeds = self.nodes[from].routes.get_closest_nodes(target, K_PARAM);
+        DisjointLookup::new(target, seeds, self.config.disjoint_paths, K_PARAM, ALPHA)
+    }
+
+    // same loop as Protocol::nodes_lookup, the paths query side by side.
+    // Returns (lookup, hops, virtual time)
+    fn nodes_lookup(&mut self, from: usize, target: &Key) -> (DisjointLookup, usize, u64) {
+        let mut lookup = self.start_lookup(from, target);
+        let mut time = 0;
+
+        while !lookup.is_done() {
+            let mut round_time = 0;
+
+            for (path, query) in lookup.next_round() {
+                let to = self.ids[&query.0.id];
+                let (latency, res) = self.find_node(from, to, target);
+                round_time = std::cmp::max(round_time, latency);
+
+                match res {
+                    Some(entries) => lookup.add_response(path, query, entries),
+                    None => lookup.add_failure(path, query),
+                }
+            }
+
+            time += round_time;
+        }
+
+        let hops = lookup.stats().rounds;
+        (lookup, hops, time)
+    }
+
+    // same loop as Protocol::value_lookup, returns (value, hops, virtual time)
+    fn value_lookup(&mut self, from: usize, k: &str) -> (Option<Vec<u8>>, usize, u64) {
+        let key = Key::new(k.to_string());
+        let mut lookup = self.start_lookup(from, &key);
+        let mut time = 0;
+
+        while !lookup.is_done() {
+            let mut round_time = 0;
+
+            for (path, query) in lookup.next_round() {
+                let to = self.ids[&query.0.id];
+                let (latency, res) = self.find_value(from, to, k);
+                round_time = std::cmp::max(round_time, latency);
+
+                match res {
+                    Some(FindValueResult::Nodes(entries)) => {
+                        lookup.add_response(path, query, entries)
+                    }
+                    Some(FindValueResult::Value(v, ttl)) => lookup.add_value(path, query, v, ttl),
+                    None => lookup.add_failure(path, query),
+                }
+            }
+
+            time += round_time;
+        }
+
+        let hops = lookup.stats().rounds;
+        (lookup.value().map(|(v, _, _)| v), hops, time)
+    }
+
+    fn join(&mut self, idx: usize) {
+        if let Some(bootstrap) = self.random_alive_node() {
+            self.add_contact(idx, bootstrap);
+
+            let id = self.nodes[idx].node.id.clone();
+            let (_, _, time) = self.nodes_lookup(idx, &id);
+            self.clock += time;
+        }
+
+        self.nodes[idx].joined = true;
+        self.stats.joins += 1;
+    }
+
+    fn fail(&mut self, idx: usize) {
+        if self.nodes[idx].alive {
+            self.nodes[idx].alive = false;
+            self.stats.failures += 1;
+        }
+    }
+
+    fn closest_alive(&self, target: &Key) -> Option<&Key> {
+        self.nodes
+            .iter()
+            .filter(|n| n.joined && n.alive)
+            .min_by_key(|n| Distance::new(&n.node.id, target))
+            .map(|n| &n.node.id)
+    }
+
+    fn lookup(&mut self, target: &Key) {
+        let from = match self.random_alive_node() {
+            Some(from) => from,
+            None => return,
+        };
+
+        let (lookup, hops, time) = self.nodes_lookup(from, target);
+        let found = lookup.result();
+        self.clock += time;
+
+        self.stats.lookups += 1;
+        self.stats.lookup_hops += hops;
+        self.stats.lookup_time += time;
+
+        if let Some(closest) = self.closest_alive(target) {
+            if found.iter().any(|nd| &nd.0.id == closest) {
+                self.stats.successful_lookups += 1;
+            }
+        }
+    }
+
+    fn put(&mut self, key: String, value: String) {
+        let from = match self.random_alive_node() {
+            Some(from) => from,
+            None => return,
+        };
+
+        let (lookup, _, time) = self.nodes_lookup(from, &Key::new(key.clone()));
+        self.clock += time;
+
+        // like Protocol::put, on the closest nodes of every path
+        for NodeAndDistance(node, _) in lookup.path_results().into_iter().flatten() {
+            let to = self.ids[&node.id];
+            if self.round_trip(to).is_some() {
+                self.nodes[to].store.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    fn get(&mut self, key: &str, expected: &str) {
+        let from = match self.random_alive_node() {
+            Some(from) => from,
+            None => return,
+        };
+
+        let (value, hops, time) = self.value_lookup(from, key);
+        self.clock += time;
+
+        self.stats.gets += 1;
+        self.stats.get_hops += hops;
+        if value.as_deref() == Some(expected.as_bytes()) {
+            self.stats.successful_gets += 1;
+        }
+    }
+}
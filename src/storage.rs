@@ -0,0 +1,300 @@
+use super::key::Key;
+use super::utils;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// longest time a pair is kept, whatever ttl it came with
+pub const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+// a stored value, it's never handed out after expires_at
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreEntry {
+    #[serde(with = "serde_bytes")]
+    pub value: Vec<u8>,
+    pub expires_at: SystemTime,
+    // we put the pair ourselves, it's kept alive and republished until publish_until
+    // (see Protocol::unpublish), then it expires like any other pair
+    pub publisher: bool,
+    #[serde(default)]
+    pub publish_until: Option<SystemTime>,
+    // last Store received for the pair
+    pub last_stored: SystemTime,
+    // last time we put the pair again, see Protocol::republish
+    pub last_republished: SystemTime,
+}
+
+impl StoreEntry {
+    pub fn new(value: Vec<u8>, ttl: Duration) -> Self {
+        let now = SystemTime::now();
+        Self {
+            value,
+            // a ttl the clock can't represent gets the pair expired right away
+            expires_at: now.checked_add(ttl.min(MAX_TTL)).unwrap_or(now),
+            publisher: false,
+            publish_until: None,
+            last_stored: now,
+            last_republished: now,
+        }
+    }
+
+    // we're still republishing the pair
+    pub fn publishing(&self, now: SystemTime) -> bool {
+        self.publisher && self.publish_until.is_some_and(|until| until > now)
+    }
+}
+
+// Where a node keeps the <key, value> pairs it's responsible for.
+// Methods take &self since the backend is shared by all the tasks of a Protocol,
+// writes fail only when the backend can't persist them. A backend may persist them
+// later (write-behind), flush returns once every change made before it is persisted
+pub trait StorageBackend: Send + Sync + Debug {
+    fn get(&self, key: &Key) -> Option<StoreEntry>;
+    fn put(&self, key: Key, entry: StoreEntry) -> io::Result<()>;
+    fn delete(&self, key: &Key) -> io::Result<Option<StoreEntry>>;
+    // snapshot of every stored pair, expired ones included
+    fn entries(&self) -> Vec<(Key, StoreEntry)>;
+    fn flush(&self) -> io::Result<()>;
+}
+
+// the default backend, everything is lost when the node stops
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<HashMap<Key, StoreEntry>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn get(&self, key: &Key) -> Option<StoreEntry> {
+        self.entries
+            .lock()
+            .expect("[FAILED] MemoryStorage::get --> Failed to acquire mutex on Entries")
+            .get(key)
+            .cloned()
+    }
+
+    fn put(&self, key: Key, entry: StoreEntry) -> io::Result<()> {
+        self.entries
+            .lock()
+            .expect("[FAILED] MemoryStorage::put --> Failed to acquire mutex on Entries")
+            .insert(key, entry);
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &Key) -> io::Result<Option<StoreEntry>> {
+        Ok(self
+            .entries
+            .lock()
+            .expect("[FAILED] MemoryStorage::delete --> Failed to acquire mutex on Entries")
+            .remove(key))
+    }
+
+    fn entries(&self) -> Vec<(Key, StoreEntry)> {
+        self.entries
+            .lock()
+            .expect("[FAILED] MemoryStorage::entries --> Failed to acquire mutex on Entries")
+            .iter()
+            .map(|(k, entry)| (k.clone(), entry.clone()))
+            .collect()
+    }
+
+    // nothing to persist
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps the pairs in memory and each of them in its own file of the directory path,
+// named after the hex of its key, see utils::write_atomically. A change only rewrites the
+// file of its pair, on tokio's blocking threads (or right away outside of a runtime):
+// put and delete are write-behind, they return before the file is written and a failed
+// write is only reported by the next flush
+#[derive(Debug)]
+pub struct FileStorage {
+    pub path: PathBuf,
+    entries: Mutex<HashMap<Key, StoreEntry>>,
+    writer: Arc<Writer>,
+}
+
+#[derive(Debug)]
+struct Writer {
+    dir: PathBuf,
+    // latest state of the pairs that aren't on disk yet, None if deleted
+    pending: Mutex<HashMap<Key, Option<StoreEntry>>>,
+    // a flush is on its way on the blocking threads
+    scheduled: AtomicBool,
+    // held while writing, pending is drained under it so an older state never lands last
+    disk: Mutex<()>,
+}
+
+impl FileStorage {
+    // loads the pairs saved in path, creating the directory if needed
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        let mut entries = HashMap::new();
+        for file in fs::read_dir(&path)? {
+            let file = file?.path();
+            // leftovers of a crash while writing are skipped
+            let key = match file.file_name().and_then(|name| name.to_str()) {
+                Some(name) => match hex::decode(name).ok().map(|k| k.try_into()) {
+                    Some(Ok(k)) => Key(k),
+                    _ => continue,
+                },
+                None => continue,
+            };
+            let entry = bincode::deserialize(&fs::read(&file)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            entries.insert(key, entry);
+        }
+
+        Ok(Self {
+            path: path.clone(),
+            entries: Mutex::new(entries),
+            writer: Arc::new(Writer {
+                dir: path,
+                pending: Mutex::new(HashMap::new()),
+                scheduled: AtomicBool::new(false),
+                disk: Mutex::new(()),
+            }),
+        })
+    }
+
+    // called with the lock on entries held, so that changes are queued in order
+    fn persist(&self, key: Key, entry: Option<StoreEntry>) -> io::Result<()> {
+        self.writer
+            .pending
+            .lock()
+            .expect("[FAILED] FileStorage::persist --> Failed to acquire mutex on Pending")
+            .insert(key, entry);
+        if self.writer.scheduled.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                let writer = self.writer.clone();
+                handle.spawn_blocking(move || {
+                    if let Err(e) = writer.flush() {
+                        eprintln!("[WARNING] FileStorage::persist --> {}", e);
+                    }
+                });
+                Ok(())
+            }
+            Err(_) => self.writer.flush(),
+        }
+    }
+}
+
+impl Writer {
+    fn flush(&self) -> io::Result<()> {
+        let _disk = self
+            .disk
+            .lock()
+            .expect("[FAILED] FileStorage::flush --> Failed to acquire mutex on Disk");
+        // changes queued from now on need another flush
+        self.scheduled.store(false, Ordering::Release);
+        let pending = std::mem::take(
+            &mut *self
+                .pending
+                .lock()
+                .expect("[FAILED] FileStorage::flush --> Failed to acquire mutex on Pending"),
+        );
+
+        let mut res = Ok(());
+        let mut failed = Vec::new();
+        for (key, entry) in pending {
+            let file = self.dir.join(hex::encode(key.0));
+            let written = match &entry {
+                Some(entry) => bincode::serialize(entry)
+                    .map_err(io::Error::other)
+                    .and_then(|bytes| utils::write_atomically(&file, &bytes)),
+                None => match fs::remove_file(&file) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => utils::sync_dir(&self.dir),
+                },
+            };
+            // the other pairs are still written
+            if let Err(e) = written {
+                failed.push((key, entry));
+                if res.is_ok() {
+                    res = Err(e);
+                }
+            }
+        }
+
+        // failed changes are tried again by the next flush, unless a newer one replaced them
+        let mut pending = self
+            .pending
+            .lock()
+            .expect("[FAILED] FileStorage::flush --> Failed to acquire mutex on Pending");
+        for (key, entry) in failed {
+            pending.entry(key).or_insert(entry);
+        }
+
+        res
+    }
+}
+
+impl StorageBackend for FileStorage {
+    fn get(&self, key: &Key) -> Option<StoreEntry> {
+        self.entries
+            .lock()
+            .expect("[FAILED] FileStorage::get --> Failed to acquire mutex on Entries")
+            .get(key)
+            .cloned()
+    }
+
+    fn put(&self, key: Key, entry: StoreEntry) -> io::Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .expect("[FAILED] FileStorage::put --> Failed to acquire mutex on Entries");
+
+        entries.insert(key.clone(), entry.clone());
+        self.persist(key, Some(entry))
+    }
+
+    fn delete(&self, key: &Key) -> io::Result<Option<StoreEntry>> {
+        let mut entries = self
+            .entries
+            .lock()
+            .expect("[FAILED] FileStorage::delete --> Failed to acquire mutex on Entries");
+
+        let removed = entries.remove(key);
+        if removed.is_some() {
+            self.persist(key.clone(), None)?;
+        }
+
+        Ok(removed)
+    }
+
+    fn entries(&self) -> Vec<(Key, StoreEntry)> {
+        self.entries
+            .lock()
+            .expect("[FAILED] FileStorage::entries --> Failed to acquire mutex on Entries")
+            .iter()
+            .map(|(k, entry)| (k.clone(), entry.clone()))
+            .collect()
+    }
+
+    // writes the pending changes before returning
+    fn flush(&self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
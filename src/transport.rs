@@ -0,0 +1,194 @@
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// A datagram oriented transport used by the network module to exchange RpcMessages.
+// Addresses are plain "ip:port" strings (IPv6 in brackets), the format of Node::get_addr.
+// Once closed, pending and future calls fail with io::ErrorKind::NotConnected
+// and the address can be bound again
+#[async_trait]
+pub trait Transport: Send + Sync + Debug {
+    async fn send_to(&self, buf: &[u8], dst: &str) -> io::Result<usize>;
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)>;
+    fn local_addr(&self) -> String;
+    fn close(&self);
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "transport closed")
+}
+
+#[derive(Debug)]
+pub struct UdpTransport {
+    // taken by close, the socket is released once in-flight calls are done with it
+    socket: Mutex<Option<Arc<UdpSocket>>>,
+    closed: watch::Sender<bool>,
+    addr: String,
+}
+
+impl UdpTransport {
+    pub async fn bind(addr: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let addr = socket.local_addr()?.to_string();
+
+        Ok(Self {
+            socket: Mutex::new(Some(Arc::new(socket))),
+            closed: watch::Sender::new(false),
+            addr,
+        })
+    }
+
+    fn socket(&self) -> io::Result<Arc<UdpSocket>> {
+        self.socket
+            .lock()
+            .expect("[FAILED] UdpTransport::socket --> Failed to acquire mutex on Socket")
+            .clone()
+            .ok_or_else(not_connected)
+    }
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    async fn send_to(&self, buf: &[u8], dst: &str) -> io::Result<usize> {
+        self.socket()?.send_to(buf, dst).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        // subscribing before looking at the socket, a close in between isn't missed
+        let mut closed = self.closed.subscribe();
+        let socket = self.socket()?;
+
+        tokio::select! {
+            res = socket.recv_from(buf) => {
+                let (len, src) = res?;
+                Ok((len, src.to_string()))
+            }
+            _ = closed.changed() => Err(not_connected()),
+        }
+    }
+
+    fn local_addr(&self) -> String {
+        self.addr.clone()
+    }
+
+    fn close(&self) {
+        self.socket
+            .lock()
+            .expect("[FAILED] UdpTransport::close --> Failed to acquire mutex on Socket")
+            .take();
+        self.closed.send_replace(true);
+    }
+}
+
+type Datagram = (Vec<u8>, String);
+
+// In-process network: every bound address gets a channel, sending a datagram
+// means pushing it into the channel of the destination address.
+// Datagrams sent to unknown addresses are dropped, just like UDP would do
+#[derive(Clone, Debug, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Datagram>>>>,
+}
+
+#[derive(Debug)]
+pub struct MemoryTransport {
+    pub addr: String,
+    receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+    network: MemoryNetwork,
+    closed: AtomicBool,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(&self, addr: &str) -> io::Result<MemoryTransport> {
+        let mut endpoints = self
+            .endpoints
+            .lock()
+            .expect("[FAILED] MemoryNetwork::bind --> Failed to acquire mutex on Endpoints");
+
+        if endpoints.contains_key(addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already bound", addr),
+            ));
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        endpoints.insert(addr.to_string(), sender);
+
+        Ok(MemoryTransport {
+            addr: addr.to_string(),
+            receiver: tokio::sync::Mutex::new(receiver),
+            network: self.clone(),
+            closed: AtomicBool::new(false),
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn send_to(&self, buf: &[u8], dst: &str) -> io::Result<usize> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(not_connected());
+        }
+
+        let endpoints =
+            self.network.endpoints.lock().expect(
+                "[FAILED] MemoryTransport::send_to --> Failed to acquire mutex on Endpoints",
+            );
+
+        if let Some(sender) = endpoints.get(dst) {
+            // a dead receiver is a node that went away, the datagram is lost
+            let _ = sender.send((buf.to_vec(), self.addr.clone()));
+        }
+
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        // the channel closes when our endpoint is removed from the network
+        let (data, src) = self
+            .receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(not_connected)?;
+
+        // same semantics as recv_from on a UdpSocket: excess bytes are discarded
+        let len = std::cmp::min(data.len(), buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+
+        Ok((len, src))
+    }
+
+    fn local_addr(&self) -> String {
+        self.addr.clone()
+    }
+
+    fn close(&self) {
+        // once closed the address may belong to another transport, it must be left alone
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Ok(mut endpoints) = self.network.endpoints.lock() {
+            endpoints.remove(&self.addr);
+        }
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -1,20 +1,13 @@
 use super::node::Node;
 use super::protocol::Protocol;
 
-use std::fs::create_dir_all;
-use std::io::Write;
-use std::net::UdpSocket;
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
 
-use super::network;
 use super::routing::{KBucket, NodeAndDistance};
 
-#[derive(Debug)]
-pub enum ChannelPayload {
-    Request((network::Request, Node)),
-    Response(network::Response),
-    NoData,
-}
-
 pub fn get_local_ip() -> Option<String> {
     let socket = match UdpSocket::bind("0.0.0.0:0") {
         Ok(s) => s,
@@ -27,19 +20,100 @@ pub fn get_local_ip() -> Option<String> {
     };
 
     match socket.local_addr() {
-        Ok(addr) => return Some(addr.ip().to_string()),
-        Err(_) => return None,
-    };
+        Ok(addr) => Some(addr.ip().to_string()),
+        Err(_) => None,
+    }
+}
+
+// splits "ip:port" (or "[ipv6]:port") into the ip, without brackets, and the port
+pub fn parse_addr(addr: &str) -> Option<(String, u16)> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Some((addr.ip().to_string(), addr.port()));
+    }
+
+    // a host name, an IPv6 address needs brackets
+    let (host, port) = addr.rsplit_once(':')?;
+    if host.is_empty() || host.contains(':') {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
 }
 
-pub fn make_req_get_res(
-    rpc: &network::Rpc,
-    req: network::Request,
-    dst: Node,
-) -> Option<network::Response> {
-    rpc.make_request(req, dst)
-        .recv()
-        .expect("[FAILED] Utils::make_req_get_res --> Failed to receive response through channel")
+// Reads a list of bootstrap nodes, one "ip:port" (or "[ipv6]:port") per line.
+// Empty lines and lines starting with '#' are skipped
+pub fn read_seed_file(path: &Path) -> io::Result<Vec<Node>> {
+    let contents = std::fs::read_to_string(path)?;
+
+    let mut nodes = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let node = parse_addr(line).map(|(ip, port)| Node::new(ip, port));
+        match node {
+            Some(node) => nodes.push(node),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid seed '{}', expected ip:port", line),
+                ))
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+// The file is never written in place: the new contents go to "<path>.tmp", which is
+// then renamed over the old file, so a crash leaves either the old or the new contents
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    write_file(path, bytes, false)
+}
+
+// same as write_atomically, but only the owner can read the file (on unix), e.g. a secret key
+pub fn write_secret(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    write_file(path, bytes, true)
+}
+
+fn write_file(path: &Path, bytes: &[u8], private: bool) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+
+    // the mode only applies to new files, a leftover of a crash would keep its own
+    match std::fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    // the rename itself only survives a crash once the directory is synced
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+        _ => sync_dir(Path::new(".")),
+    }
+}
+
+// not supported on every platform, e.g. windows can't open directories
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
 }
 
 pub fn dump_interface_state(interface: &Protocol, path: &str) {
@@ -50,10 +124,6 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
         .routes
         .lock()
         .expect("[FAILED] Utils::dump_interface_state --> Failed to acquire mutex on Routes");
-    let st = interface
-        .store
-        .lock()
-        .expect("[FAILED] Utils::dump_interface_state --> Failed to acquire mutex on Store");
 
     let flattened: Vec<&KBucket> = rt.kbuckets.iter().collect();
 
@@ -65,6 +135,7 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
                     "ip": n.ip,
                     "port": n.port,
                     "id": format!("{:?}", n.id),
+                    "last_seen": format!("{:?}", rt.last_seen.get(&n.id)),
                 },
                 "size": kb.size,
             });
@@ -73,9 +144,15 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
     }
 
     let mut parsed_store = vec![];
-    // parse store
-    for (k, v) in &*st {
-        let obj = serde_json::json!({ k: v });
+    // parse store, keys and values are binary
+    for (k, entry) in interface.store.entries() {
+        let obj = serde_json::json!({ hex::encode(k.0): {
+            "value": hex::encode(&entry.value),
+            "expires_at": format!("{:?}", entry.expires_at),
+            "publisher": entry.publisher,
+            "last_stored": format!("{:?}", entry.last_stored),
+            "last_republished": format!("{:?}", entry.last_republished),
+        }});
         parsed_store.push(obj);
     }
 
@@ -94,9 +171,27 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
             "kbuckets": parsed_buckets,
         },
         "store": parsed_store,
+        "config": format!("{:?}", interface.config),
+        "stats": format!("{:?}", interface.stats),
+        "handoffs": format!(
+            "{:?}",
+            interface
+                .handoffs
+                .lock()
+                .expect("[FAILED] Utils::dump_interface_state --> Failed to acquire mutex on Handoffs")
+        ),
         "rpc": {
-            "socket": format!("{:?}", interface.rpc.socket),
-            "pending": format!("{:?}", interface.rpc.pending.lock().unwrap()),
+            "transport": format!("{:?}", interface.rpc.transport),
+            "pending": format!(
+                "{:?}",
+                interface
+                    .rpc
+                    .pending
+                    .lock()
+                    .expect("[FAILED] Utils::dump_interface_state --> Failed to acquire mutex on Pending")
+            ),
+            "codec": format!("{:?}", interface.rpc.codec),
+            "stats": format!("{:?}", interface.rpc.stats),
             "node": {
                 "ip": interface.rpc.node.ip,
                 "port": interface.rpc.node.port,
@@ -108,7 +203,7 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
     // write to json file
     let mut file = std::fs::File::create(path)
         .expect("[FAILED] Utils::dump_interface_state --> Unable to create dump file");
-    file.write_all(&json.to_string().as_bytes())
+    file.write_all(json.to_string().as_bytes())
         .expect("[FAILED] Utils::dump_interface_state --> Unable to write to dump file");
 
     // write also to a .plantuml file
@@ -119,7 +214,7 @@ pub fn dump_interface_state(interface: &Protocol, path: &str) {
         .expect("[FAILED] Utils::dump_interface_state --> Unable to write to dump file");
 
     diagram
-        .write_all(&json.to_string().as_bytes())
+        .write_all(json.to_string().as_bytes())
         .expect("[FAILED] Utils::dump_interface_state --> Unable to write to dump file");
 
     diagram
@@ -152,6 +247,6 @@ pub fn dump_node_and_distance(
 
     let mut file = std::fs::File::create(path)
         .expect("[FAILED] Utils::dump_node_and_distance --> Unable to create dump file");
-    file.write_all(&json.to_string().as_bytes())
+    file.write_all(json.to_string().as_bytes())
         .expect("[FAILED] Utils::dump_node_and_distance --> Unable to write to dump file");
 }